@@ -0,0 +1,146 @@
+use crate::error::WaitError;
+use crate::select::sealed::AsWaiter;
+use crate::select::Watcher;
+use crate::waiter_map::{WaiterGuard, WaiterMap};
+
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::time::Instant;
+
+/// Batch waiter that waits for all the keys against one deadline
+#[derive(Debug)]
+pub struct BatchWaiter<'a, K: Hash + Eq, T> {
+    guards: Vec<WaiterGuard<'a, K, T>>,
+}
+
+impl<K: Hash + Eq, T> WaiterMap<K, T> {
+    /// register all the keys for a batch wait, a key in use is given back
+    /// like `new_quorum`
+    pub fn new_batch<I: IntoIterator<Item = K>>(&self, keys: I) -> Result<BatchWaiter<'_, K, T>, K>
+    where
+        K: Clone,
+    {
+        let guards = self.try_new_waiters(keys)?;
+        Ok(BatchWaiter { guards })
+    }
+}
+
+impl<'a, K: Hash + Eq + Clone + Debug, T> BatchWaiter<'a, K, T> {
+    /// number of the keys in the batch
+    pub fn len(&self) -> usize {
+        self.guards.len()
+    }
+
+    /// return true if there is no key in the batch
+    pub fn is_empty(&self) -> bool {
+        self.guards.is_empty()
+    }
+
+    /// wait for all the rsps until the deadline
+    ///
+    /// return the result of each key in the registered order, the keys that
+    /// missed the deadline get a `WaitError::Timeout`
+    pub fn wait_all(self, deadline: Instant) -> Vec<(K, Result<T, WaitError>)> {
+        let mut results: Vec<Option<Result<T, WaitError>>> =
+            self.guards.iter().map(|_| None).collect();
+        let watcher = Watcher::new(self.guards.iter().map(|g| g.waiter()));
+
+        let timeout = loop {
+            let mut done = true;
+            for (guard, ret) in self.guards.iter().zip(results.iter_mut()) {
+                if ret.is_none() {
+                    *ret = guard.waiter().try_rsp();
+                    done &= ret.is_some();
+                }
+            }
+            if done {
+                break None;
+            }
+            if let Err(e) = watcher.park(Some(deadline)) {
+                break Some(e);
+            }
+        };
+        drop(watcher);
+
+        self.guards
+            .iter()
+            .zip(results)
+            .map(|(guard, ret)| {
+                let key = guard.key().clone();
+                let ret = ret
+                    .or_else(|| guard.waiter().try_rsp())
+                    .or_else(|| timeout.clone().map(Err))
+                    .expect("all rsps are ready without timeout");
+                let ret = ret.map_err(|e| e.with_key(format!("{key:?}")));
+                (key, ret)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::go;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn batch_wait_all() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let rmap = req_map.clone();
+        let batch = req_map.new_batch(0..4).unwrap();
+        assert_eq!(batch.len(), 4);
+        assert_eq!(req_map.new_batch([4, 2]).unwrap_err(), 2);
+
+        go!(move || {
+            for i in [3, 0, 1] {
+                rmap.set_rsp(&i, i * 10).unwrap();
+            }
+        });
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let results = batch.wait_all(deadline);
+        let keys: Vec<_> = results.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, [0, 1, 2, 3]);
+        assert_eq!(results[0].1, Ok(0));
+        assert_eq!(results[1].1, Ok(10));
+        assert_eq!(results[3].1, Ok(30));
+        let err = results[2].1.clone().unwrap_err();
+        assert!(err.is_timeout());
+        assert_eq!(err.key(), Some("2"));
+
+        // all the keys are removed
+        assert!(req_map.set_rsp(&2, 20).is_err());
+    }
+
+    #[test]
+    fn batch_all_ready() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let batch = req_map.new_batch([1, 2]).unwrap();
+        req_map.set_rsp(&1, 1).unwrap();
+        req_map.set_rsp(&2, 2).unwrap();
+        let start = Instant::now();
+        let results = batch.wait_all(start + Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(results, [(1, Ok(1)), (2, Ok(2))]);
+    }
+
+    #[test]
+    fn batch_early_rsp() {
+        let req_map = WaiterMap::<usize, usize>::with_early_rsp(4, Duration::from_secs(10));
+        req_map.set_rsp(&1, 10).unwrap();
+        req_map.set_rsp(&3, 30).unwrap();
+        let busy = req_map.new_waiter(2);
+        // the rollback keeps the buffered rsp
+        assert_eq!(req_map.new_batch([1, 2]).unwrap_err(), 2);
+        drop(busy);
+        assert_eq!(req_map.new_waiter(1).wait_rsp(None), Ok(10));
+
+        // the buffered rsps are delivered once all the keys are in
+        let batch = req_map.new_batch([2, 3]).unwrap();
+        req_map.set_rsp(&2, 20).unwrap();
+        let results = batch.wait_all(Instant::now() + Duration::from_secs(10));
+        assert_eq!(results, [(2, Ok(20)), (3, Ok(30))]);
+    }
+}
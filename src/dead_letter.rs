@@ -0,0 +1,200 @@
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// why a rsp could not be delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum DeadLetterReason {
+    /// no waiter was ever registered with the key, or it's too old to tell
+    NeverRegistered,
+    /// the waiter was gone without a rsp, e.g. the wait timed out
+    TimedOut,
+    /// the waiter was canceled or the map was shut down
+    Canceled,
+    /// the waiter already got its rsp
+    Consumed,
+}
+
+impl fmt::Display for DeadLetterReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeadLetterReason::NeverRegistered => write!(f, "never registered"),
+            DeadLetterReason::TimedOut => write!(f, "already timed out"),
+            DeadLetterReason::Canceled => write!(f, "already canceled"),
+            DeadLetterReason::Consumed => write!(f, "already consumed"),
+        }
+    }
+}
+
+/// number of dead letters for each reason
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeadLetterStats {
+    pub never_registered: u64,
+    pub timed_out: u64,
+    pub canceled: u64,
+    pub consumed: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Counters([AtomicU64; 4]);
+
+impl Counters {
+    pub fn count(&self, reason: DeadLetterReason) {
+        let i = match reason {
+            DeadLetterReason::NeverRegistered => 0,
+            DeadLetterReason::TimedOut => 1,
+            DeadLetterReason::Canceled => 2,
+            DeadLetterReason::Consumed => 3,
+        };
+        self.0[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> DeadLetterStats {
+        let [never_registered, timed_out, canceled, consumed] =
+            self.0.each_ref().map(|c| c.load(Ordering::Relaxed));
+        DeadLetterStats {
+            never_registered,
+            timed_out,
+            canceled,
+            consumed,
+        }
+    }
+}
+
+type Handler<K, T> = Box<dyn Fn(&K, &T, DeadLetterReason) + Send + Sync>;
+
+// the retired keys of a map, the oldest is forgotten first
+struct Retired<K> {
+    reasons: HashMap<K, DeadLetterReason>,
+    order: VecDeque<K>,
+}
+
+/// the dead letter handler of a `WaiterMap`
+///
+/// it remembers why the last `history` keys were retired, so that a late
+/// rsp could be told apart from a bogus one
+pub(crate) struct DeadLetters<K, T> {
+    handler: Handler<K, T>,
+    counters: Counters,
+    retired: Mutex<Retired<K>>,
+    history: usize,
+    clone_key: fn(&K) -> K,
+}
+
+impl<K: Hash + Eq, T> DeadLetters<K, T> {
+    pub fn new(history: usize, handler: Handler<K, T>) -> Self
+    where
+        K: Clone,
+    {
+        DeadLetters {
+            handler,
+            counters: Counters::default(),
+            retired: Mutex::new(Retired {
+                reasons: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+            history,
+            clone_key: K::clone,
+        }
+    }
+
+    fn retired(&self) -> MutexGuard<'_, Retired<K>> {
+        self.retired.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// remember why the key is retired, a late rsp would get the reason
+    pub fn retire(&self, id: &K, reason: DeadLetterReason) {
+        if self.history == 0 {
+            return;
+        }
+        let mut retired = self.retired();
+        let Retired { reasons, order } = &mut *retired;
+        if reasons.insert((self.clone_key)(id), reason).is_none() {
+            order.push_back((self.clone_key)(id));
+        }
+        while order.len() > self.history {
+            if let Some(old) = order.pop_front() {
+                reasons.remove(&old);
+            }
+        }
+    }
+
+    /// the key is registered again, forget the retired reason
+    pub fn revive(&self, id: &K) {
+        let mut retired = self.retired();
+        if retired.reasons.remove(id).is_some() {
+            retired.order.retain(|k| k != id);
+        }
+    }
+
+    pub fn handle(&self, id: &K, rsp: &T) {
+        let reason = self.retired().reasons.get(id).copied();
+        let reason = reason.unwrap_or(DeadLetterReason::NeverRegistered);
+        self.counters.count(reason);
+        (self.handler)(id, rsp, reason)
+    }
+
+    pub fn stats(&self) -> DeadLetterStats {
+        self.counters.stats()
+    }
+}
+
+type TokenHandler<T> = Arc<dyn Fn(u64, &T, DeadLetterReason) + Send + Sync>;
+
+// the dead letters of `TokenWaiter<T>`, one for each rsp type
+struct TokenDeadLetters<T> {
+    handler: Mutex<Option<TokenHandler<T>>>,
+    counters: Counters,
+}
+
+type AnyDeadLetters = Arc<dyn Any + Send + Sync>;
+
+static TOKEN_DEAD_LETTERS: Mutex<Vec<(TypeId, AnyDeadLetters)>> = Mutex::new(Vec::new());
+
+fn token_dead_letters<T: Send + 'static>() -> Arc<TokenDeadLetters<T>> {
+    let mut all = TOKEN_DEAD_LETTERS.lock().unwrap_or_else(|e| e.into_inner());
+    let type_id = TypeId::of::<T>();
+    let letters = match all.iter().find(|(id, _)| *id == type_id) {
+        Some((_, letters)) => letters.clone(),
+        None => {
+            let letters: AnyDeadLetters = Arc::new(TokenDeadLetters::<T> {
+                handler: Mutex::new(None),
+                counters: Counters::default(),
+            });
+            all.push((type_id, letters.clone()));
+            letters
+        }
+    };
+    // the entry is always created with the matching type
+    letters.downcast().expect("dead letter type mismatch")
+}
+
+/// install the dead letter handler for the `TokenWaiter<T>` rsps
+pub(crate) fn set_token_handler<T: Send + 'static>(handler: TokenHandler<T>) {
+    let letters = token_dead_letters::<T>();
+    *letters.handler.lock().unwrap_or_else(|e| e.into_inner()) = Some(handler);
+}
+
+pub(crate) fn handle_token<T: Send + 'static>(id: u64, rsp: &T, reason: DeadLetterReason) {
+    let letters = token_dead_letters::<T>();
+    letters.counters.count(reason);
+    // don't call the handler with the lock held
+    let handler = letters
+        .handler
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    if let Some(handler) = handler {
+        handler(id, rsp, reason);
+    }
+}
+
+pub(crate) fn token_stats<T: Send + 'static>() -> DeadLetterStats {
+    token_dead_letters::<T>().counters.stats()
+}
@@ -0,0 +1,79 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// error returned when waiting for a response
+///
+/// `key` is the debug format of the waiter key when it's known
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum WaitError {
+    /// no response arrived before the timeout
+    Timeout {
+        key: Option<String>,
+        elapsed: Duration,
+    },
+    /// the wait is canceled
+    Canceled { key: Option<String> },
+    /// the waiter map is shut down
+    Shutdown { key: Option<String> },
+    /// the responder is gone without sending a response
+    ResponderDropped { key: Option<String> },
+}
+
+impl WaitError {
+    /// the debug format of the key that the waiter is waiting for
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            WaitError::Timeout { key, .. }
+            | WaitError::Canceled { key }
+            | WaitError::Shutdown { key }
+            | WaitError::ResponderDropped { key } => key.as_deref(),
+        }
+    }
+
+    /// return true if the error is a timeout
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, WaitError::Timeout { .. })
+    }
+
+    pub(crate) fn with_key(mut self, k: String) -> Self {
+        match &mut self {
+            WaitError::Timeout { key, .. }
+            | WaitError::Canceled { key }
+            | WaitError::Shutdown { key }
+            | WaitError::ResponderDropped { key } => *key = Some(k),
+        }
+        self
+    }
+}
+
+impl fmt::Display for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WaitError::Timeout { elapsed, .. } => write!(f, "wait rsp timeout after {elapsed:?}")?,
+            WaitError::Canceled { .. } => write!(f, "wait rsp canceled")?,
+            WaitError::Shutdown { .. } => write!(f, "waiter map is shut down")?,
+            WaitError::ResponderDropped { .. } => write!(f, "responder dropped")?,
+        }
+        match self.key() {
+            Some(key) => write!(f, ", key = {key}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for WaitError {}
+
+impl From<WaitError> for io::Error {
+    fn from(e: WaitError) -> Self {
+        let kind = match e {
+            WaitError::Timeout { .. } => io::ErrorKind::TimedOut,
+            WaitError::Canceled { .. } | WaitError::Shutdown { .. } => io::ErrorKind::NotFound,
+            WaitError::ResponderDropped { .. } => io::ErrorKind::BrokenPipe,
+        };
+        io::Error::new(kind, e)
+    }
+}
@@ -0,0 +1,293 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+
+use crate::token_waiter::Error;
+
+/// the id type from `TokenWaiter::id()`, it carries the rsp type
+///
+/// an id of another rsp type fails to compile
+///
+/// ```compile_fail
+/// use co_waiter::TokenWaiter;
+///
+/// let waiter = TokenWaiter::<String>::new();
+/// let id = waiter.id().unwrap();
+/// TokenWaiter::<u8>::set_rsp(id, 0);
+/// ```
+///
+/// an id that crosses a serialization boundary as `u64` or the text form
+/// is checked at run time, the rsp of a mismatched type is rejected as never
+/// registered
+pub struct ID<T>(pub(crate) u64, PhantomData<fn() -> T>);
+
+impl<T> fmt::Debug for ID<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ID").field(&self.0).finish()
+    }
+}
+
+impl<T> From<u64> for ID<T> {
+    fn from(id: u64) -> Self {
+        ID(id, PhantomData)
+    }
+}
+
+impl<T> From<ID<T>> for u64 {
+    fn from(id: ID<T>) -> Self {
+        id.0
+    }
+}
+
+/// the compact id type from `TokenWaiter::id32()`, it fits the 32 bit
+/// correlation field of a protocol
+///
+/// at most 65536 compact ids could be outstanding at the same time, and the
+/// generation of a slot wraps around after 65535 rounds, so a very late rsp
+/// to a busy process could in theory match a newer id
+pub struct ID32<T>(pub(crate) NonZeroU32, PhantomData<fn() -> T>);
+
+impl<T> fmt::Debug for ID32<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ID32").field(&self.0).finish()
+    }
+}
+
+impl<T> TryFrom<u32> for ID32<T> {
+    type Error = Error;
+
+    fn try_from(id: u32) -> Result<Self, Error> {
+        let id = NonZeroU32::new(id).ok_or(Error::Invalid)?;
+        Ok(ID32(id, PhantomData))
+    }
+}
+
+impl<T> From<ID32<T>> for u32 {
+    fn from(id: ID32<T>) -> Self {
+        id.0.get()
+    }
+}
+
+/// version tag of the text form of the ids
+const VERSION: &str = "v1";
+
+// fletcher-16 over the version and the big endian id bytes
+fn checksum(bytes: &[u8]) -> u16 {
+    let (mut a, mut b) = (0u16, 0u16);
+    for byte in VERSION.bytes().chain(bytes.iter().copied()) {
+        a = (a + u16::from(byte)) % 255;
+        b = (b + a) % 255;
+    }
+    (b << 8) | a
+}
+
+// value of an ascii hex digit, it's already checked
+fn hex_digit(b: u8) -> u8 {
+    match b {
+        b'0'..=b'9' => b - b'0',
+        b'a'..=b'f' => b - b'a' + 10,
+        _ => b - b'A' + 10,
+    }
+}
+
+// parse `v1-<hex>-<checksum>`, the hex part has exactly `N` bytes
+fn parse<const N: usize>(s: &str) -> Result<[u8; N], ParseIdError> {
+    let mut parts = s.split('-');
+    if parts.next() != Some(VERSION) {
+        return Err(ParseIdError::Version);
+    }
+    let (hex, check) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(hex), Some(check), None) if hex.len() == N * 2 && check.len() == 4 => (hex, check),
+        _ => return Err(ParseIdError::Format),
+    };
+    // only plain hex digits, no sign and no multi byte chars to slice through
+    let is_hex = |s: &str| s.bytes().all(|b| b.is_ascii_hexdigit());
+    if !is_hex(hex) || !is_hex(check) {
+        return Err(ParseIdError::Format);
+    }
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = (hex_digit(pair[0]) << 4) | hex_digit(pair[1]);
+    }
+    let check = check
+        .bytes()
+        .fold(0u16, |check, b| (check << 4) | u16::from(hex_digit(b)));
+    match checksum(&bytes) == check {
+        true => Ok(bytes),
+        false => Err(ParseIdError::Checksum),
+    }
+}
+
+/// error returned when parsing the text form of an id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ParseIdError {
+    /// the version tag is missing or unknown
+    Version,
+    /// the text is not a well formed id
+    Format,
+    /// the checksum doesn't match
+    Checksum,
+}
+
+impl fmt::Display for ParseIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseIdError::Version => write!(f, "unknown id version"),
+            ParseIdError::Format => write!(f, "malformed id"),
+            ParseIdError::Checksum => write!(f, "id checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for ParseIdError {}
+
+/// the text form is `v1-<16 hex digits>-<4 hex digits checksum>`
+impl<T> fmt::Display for ID<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let check = checksum(&self.0.to_be_bytes());
+        write!(f, "{VERSION}-{:016x}-{check:04x}", self.0)
+    }
+}
+
+impl<T> FromStr for ID<T> {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<Self, ParseIdError> {
+        parse(s).map(|bytes| ID::from(u64::from_be_bytes(bytes)))
+    }
+}
+
+/// the text form is `v1-<8 hex digits>-<4 hex digits checksum>`
+impl<T> fmt::Display for ID32<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let check = checksum(&self.0.get().to_be_bytes());
+        write!(f, "{VERSION}-{:08x}-{check:04x}", self.0)
+    }
+}
+
+impl<T> FromStr for ID32<T> {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<Self, ParseIdError> {
+        let id = parse(s).map(u32::from_be_bytes)?;
+        ID32::try_from(id).map_err(|_| ParseIdError::Format)
+    }
+}
+
+/// the ids are in the text form for the human readable formats, and plain
+/// integers for the others
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::*;
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    impl<T> Serialize for ID<T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match serializer.is_human_readable() {
+                true => serializer.collect_str(self),
+                false => serializer.serialize_u64(self.0),
+            }
+        }
+    }
+
+    impl<'de, T> Deserialize<'de> for ID<T> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            match deserializer.is_human_readable() {
+                true => String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(D::Error::custom),
+                false => u64::deserialize(deserializer).map(ID::from),
+            }
+        }
+    }
+
+    impl<T> Serialize for ID32<T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match serializer.is_human_readable() {
+                true => serializer.collect_str(self),
+                false => serializer.serialize_u32(self.0.get()),
+            }
+        }
+    }
+
+    impl<'de, T> Deserialize<'de> for ID32<T> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            match deserializer.is_human_readable() {
+                true => String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(D::Error::custom),
+                false => {
+                    let id = u32::deserialize(deserializer)?;
+                    ID32::try_from(id).map_err(D::Error::custom)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_text_form() {
+        let id = ID::<usize>::from(0x0123_4567_89ab_cdef);
+        let text = id.to_string();
+        assert!(text.starts_with("v1-0123456789abcdef-"));
+        assert_eq!(
+            u64::from(text.parse::<ID<usize>>().unwrap()),
+            0x0123_4567_89ab_cdef
+        );
+
+        let id = ID32::<usize>::try_from(42).unwrap();
+        let text = id.to_string();
+        assert_eq!(u32::from(text.parse::<ID32<usize>>().unwrap()), 42);
+        // the widths don't mix
+        assert_eq!(text.parse::<ID<usize>>().unwrap_err(), ParseIdError::Format);
+    }
+
+    #[test]
+    fn id_malformed() {
+        let text = ID::<usize>::from(42).to_string();
+        let parse = |s: &str| s.parse::<ID<usize>>().map(u64::from);
+        assert_eq!(parse(&text), Ok(42));
+        assert_eq!(parse(&text.replace("v1", "v2")), Err(ParseIdError::Version));
+        assert_eq!(parse("42"), Err(ParseIdError::Version));
+        assert_eq!(parse(&text[..text.len() - 1]), Err(ParseIdError::Format));
+        assert_eq!(parse(&format!("{text}-0")), Err(ParseIdError::Format));
+        assert_eq!(
+            parse(&text.replacen('0', "g", 1)),
+            Err(ParseIdError::Format)
+        );
+        // multi byte chars and signs are not digits
+        assert_eq!(parse("v1-a€€€€€-0000"), Err(ParseIdError::Format));
+        assert_eq!(parse("v1-+0+0+0+0+0+0+00f-6ab6"), Err(ParseIdError::Format));
+        assert_eq!(parse("v1-000000000000000f-+ab6"), Err(ParseIdError::Format));
+        // a typo in the digits is caught by the checksum
+        let typo = text.replacen("2a", "2b", 1);
+        assert_eq!(parse(&typo), Err(ParseIdError::Checksum));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn id_serde() {
+        let id = ID::<usize>::from(42);
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{id}\""));
+        let id: ID<usize> = serde_json::from_str(&json).unwrap();
+        assert_eq!(u64::from(id), 42);
+        let bad = json.replacen("2a", "2b", 1);
+        assert!(serde_json::from_str::<ID<usize>>(&bad).is_err());
+
+        let id = ID32::<usize>::try_from(42).unwrap();
+        let json = serde_json::to_string(&id).unwrap();
+        let id: ID32<usize> = serde_json::from_str(&json).unwrap();
+        assert_eq!(u32::from(id), 42);
+
+        let json = serde_json::to_string(&Error::InUse).unwrap();
+        assert_eq!(serde_json::from_str::<Error>(&json).unwrap(), Error::InUse);
+    }
+}
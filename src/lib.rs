@@ -1,6 +1,29 @@
+#![forbid(unsafe_code)]
+
+mod batch;
+mod dead_letter;
+mod error;
+mod id;
+mod oneshot;
+mod quorum;
+mod registry;
+mod select;
+mod stream_waiter;
 mod token_waiter;
+#[cfg(feature = "async")]
+mod wait_future;
 mod waiter;
 mod waiter_map;
 
-pub use token_waiter::{TokenWaiter, ID};
-pub use waiter_map::{WaiterGuard, WaiterMap};
+pub use batch::BatchWaiter;
+pub use dead_letter::{DeadLetterReason, DeadLetterStats};
+pub use error::WaitError;
+pub use id::{ParseIdError, ID, ID32};
+pub use oneshot::{oneshot, Receiver, Responder};
+pub use quorum::QuorumWaiter;
+pub use select::{wait_any, Others, Waitable};
+pub use stream_waiter::{StreamMode, StreamWaiter};
+pub use token_waiter::{Error as IdError, RspError, TokenWaiter};
+#[cfg(feature = "async")]
+pub use wait_future::WaitRsp;
+pub use waiter_map::{DuplicatePolicy, OwnedWaiterGuard, WaiterGuard, WaiterMap};
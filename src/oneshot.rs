@@ -0,0 +1,152 @@
+use crate::error::WaitError;
+use crate::select::sealed::AsWaiter;
+#[cfg(feature = "async")]
+use crate::wait_future::WaitRsp;
+use crate::waiter::Waiter;
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// create a oneshot pair, the `Receiver` waits for the rsp sent by the `Responder`
+pub fn oneshot<T>() -> (Responder<T>, Receiver<T>) {
+    let waiter = Arc::new(Waiter::new());
+    let responder = Responder {
+        waiter: Some(waiter.clone()),
+    };
+    (responder, Receiver { waiter })
+}
+
+/// the sending half of a oneshot pair
+///
+/// dropping it without sending wakes the receiver with
+/// `WaitError::ResponderDropped`
+pub struct Responder<T> {
+    waiter: Option<Arc<Waiter<T>>>,
+}
+
+impl<T> Responder<T> {
+    /// send the rsp, give it back if the receiver is already gone or
+    /// canceled by `wait_any`
+    pub fn send(mut self, rsp: T) -> Result<(), T> {
+        let waiter = self.waiter.take().expect("responder already used");
+        if Arc::strong_count(&waiter) == 1 || waiter.is_canceled() {
+            return Err(rsp);
+        }
+        waiter.set_rsp(rsp);
+        Ok(())
+    }
+
+    /// return true if the receiver is dropped or canceled
+    pub fn is_closed(&self) -> bool {
+        self.waiter
+            .as_ref()
+            .is_none_or(|w| Arc::strong_count(w) == 1 || w.is_canceled())
+    }
+}
+
+impl<T> Drop for Responder<T> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            waiter.responder_dropped();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Responder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Responder{{ ... }}")
+    }
+}
+
+/// the receiving half of a oneshot pair
+pub struct Receiver<T> {
+    waiter: Arc<Waiter<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// wait for the rsp
+    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> Result<T, WaitError> {
+        self.waiter.wait_rsp(timeout)
+    }
+
+    /// wait for the rsp until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> Result<T, WaitError> {
+        self.waiter.wait_rsp_until(deadline)
+    }
+
+    /// wait for the rsp from an async context
+    #[cfg(feature = "async")]
+    pub fn wait_rsp_async<D: Into<Option<Duration>>>(&self, timeout: D) -> WaitRsp<'_, T> {
+        WaitRsp::new(&self.waiter, None, timeout.into())
+    }
+}
+
+impl<T> AsWaiter<T> for Receiver<T> {
+    fn waiter(&self) -> &Waiter<T> {
+        &self.waiter
+    }
+
+    fn key(&self) -> Option<String> {
+        None
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Receiver{{ ... }}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::go;
+
+    #[test]
+    fn oneshot_send() {
+        let result = go!(|| {
+            let (tx, rx) = oneshot();
+            go!(move || tx.send(42).unwrap());
+            rx.wait_rsp(None)
+        })
+        .join()
+        .unwrap();
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[test]
+    fn oneshot_responder_dropped() {
+        let result = go!(|| {
+            let (tx, rx) = oneshot::<usize>();
+            go!(move || drop(tx));
+            rx.wait_rsp(Duration::from_secs(10))
+        })
+        .join()
+        .unwrap();
+        assert_eq!(result, Err(WaitError::ResponderDropped { key: None }));
+    }
+
+    #[test]
+    fn oneshot_receiver_dropped() {
+        let (tx, rx) = oneshot();
+        assert!(!tx.is_closed());
+        drop(rx);
+        assert!(tx.is_closed());
+        assert_eq!(tx.send(42), Err(42));
+    }
+
+    #[test]
+    fn oneshot_canceled() {
+        use crate::{wait_any, Others};
+        let (tx1, rx1) = oneshot::<usize>();
+        let (tx2, rx2) = oneshot::<usize>();
+        tx1.send(1).unwrap();
+        let ret = wait_any(&[&rx1, &rx2], None, Others::Cancel);
+        assert_eq!(ret, Ok((0, 1)));
+        // the late rsp of the canceled receiver is given back
+        assert!(tx2.is_closed());
+        assert_eq!(tx2.send(2), Err(2));
+        assert_eq!(rx2.wait_rsp(None), Err(WaitError::Canceled { key: None }));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::error::WaitError;
+use crate::select::sealed::AsWaiter;
+use crate::select::Watcher;
+use crate::waiter_map::{WaiterGuard, WaiterMap};
+
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::time::Duration;
+
+/// Quorum waiter that waits for k of n keyed responses
+///
+/// all the keys are registered in the map when it's created, and the ones
+/// that are still waiting are removed when it's dropped
+#[derive(Debug)]
+pub struct QuorumWaiter<'a, K: Hash + Eq, T> {
+    guards: Vec<WaiterGuard<'a, K, T>>,
+    // index of the guards that are still waiting
+    pending: Vec<usize>,
+    rsps: Vec<(K, T)>,
+    // number of the rsps already returned by `wait`
+    returned: usize,
+    err: Option<WaitError>,
+}
+
+impl<K: Hash + Eq, T> WaiterMap<K, T> {
+    /// register all the keys for a quorum wait
+    ///
+    /// give back the first key that is already in use, in which case none
+    /// of the keys is registered
+    pub fn new_quorum<I: IntoIterator<Item = K>>(
+        &self,
+        keys: I,
+    ) -> Result<QuorumWaiter<'_, K, T>, K>
+    where
+        K: Clone,
+    {
+        let guards = self.try_new_waiters(keys)?;
+        Ok(QuorumWaiter {
+            pending: (0..guards.len()).collect(),
+            guards,
+            rsps: Vec::new(),
+            returned: 0,
+            err: None,
+        })
+    }
+}
+
+impl<'a, K: Hash + Eq + Clone + Debug, T> QuorumWaiter<'a, K, T> {
+    /// wait until `k` rsps are collected
+    ///
+    /// the collected `(key, rsp)` pairs are taken out and returned, on error
+    /// the pairs arrived so far are kept and could be got by `responses`
+    /// it fails early if too many waiters failed to reach `k`
+    ///
+    /// the rsps returned by the previous waits are not counted again, so it
+    /// panics if `k` is more than the number of the keys not returned yet
+    pub fn wait<D: Into<Option<Duration>>>(
+        &mut self,
+        k: usize,
+        timeout: D,
+    ) -> Result<Vec<(K, T)>, WaitError> {
+        assert!(
+            k <= self.guards.len() - self.returned,
+            "quorum is more than the keys"
+        );
+        let watcher = Watcher::new(self.pending.iter().map(|&i| self.guards[i].waiter()));
+        let deadline = timeout.into().and_then(|t| watcher.start.checked_add(t));
+
+        loop {
+            poll(
+                &self.guards,
+                &mut self.pending,
+                &mut self.rsps,
+                &mut self.err,
+            );
+            if self.rsps.len() >= k {
+                self.returned += self.rsps.len();
+                return Ok(std::mem::take(&mut self.rsps));
+            }
+            if self.rsps.len() + self.pending.len() < k {
+                // only a failed waiter could make the quorum unreachable
+                return Err(self.err.clone().expect("no waiter failed"));
+            }
+            watcher.park(deadline)?;
+        }
+    }
+
+    /// the rsps that are collected but not returned yet
+    pub fn responses(&self) -> &[(K, T)] {
+        &self.rsps
+    }
+
+    /// number of the keys that are still waiting for the rsp
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+// collect the arrived rsps from the pending guards
+fn poll<K: Hash + Eq + Clone + Debug, T>(
+    guards: &[WaiterGuard<'_, K, T>],
+    pending: &mut Vec<usize>,
+    rsps: &mut Vec<(K, T)>,
+    err: &mut Option<WaitError>,
+) {
+    pending.retain(|&i| {
+        let guard = &guards[i];
+        match guard.waiter().try_rsp() {
+            Some(Ok(rsp)) => rsps.push((guard.key().clone(), rsp)),
+            Some(Err(e)) => *err = Some(e.with_key(format!("{:?}", guard.key()))),
+            None => return true,
+        }
+        false
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use may::go;
+    use std::sync::Arc;
+
+    #[test]
+    fn quorum_wait() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let rmap = req_map.clone();
+        let mut quorum = req_map.new_quorum(0..5).unwrap();
+        assert_eq!(req_map.new_quorum([5, 3]).unwrap_err(), 3);
+
+        go!(move || {
+            for i in [4, 1, 2] {
+                rmap.set_rsp(&i, i * 10).unwrap();
+            }
+        });
+
+        let mut rsps = quorum.wait(3, Duration::from_secs(10)).unwrap();
+        rsps.sort();
+        assert_eq!(rsps, [(1, 10), (2, 20), (4, 40)]);
+        assert_eq!(quorum.pending(), 2);
+
+        // the other keys are cleaned up after drop
+        req_map.set_rsp(&0, 0).unwrap();
+        drop(quorum);
+        assert!(req_map.set_rsp(&3, 30).is_err());
+        assert!(req_map.new_quorum([0, 5]).is_ok());
+    }
+
+    #[test]
+    fn quorum_fail() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let mut quorum = req_map.new_quorum(0..3).unwrap();
+        req_map.set_rsp(&0, 0).unwrap();
+        let ret = quorum.wait(2, Duration::from_millis(20));
+        assert!(ret.unwrap_err().is_timeout());
+        assert_eq!(quorum.responses(), [(0, 0)]);
+
+        // can't reach the quorum after cancel
+        req_map.cancel_all();
+        let ret = quorum.wait(2, None);
+        assert!(matches!(ret, Err(WaitError::Canceled { .. })));
+    }
+
+    #[test]
+    #[should_panic(expected = "quorum is more than the keys")]
+    fn quorum_after_returned() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let mut quorum = req_map.new_quorum(0..4).unwrap();
+        for i in 0..3 {
+            req_map.set_rsp(&i, i).unwrap();
+        }
+        assert_eq!(quorum.wait(2, None).unwrap().len(), 3);
+        // only one key is left
+        assert_eq!(quorum.pending(), 1);
+        quorum.wait(2, None).ok();
+    }
+
+    #[test]
+    #[should_panic(expected = "quorum is more than the keys")]
+    fn quorum_too_large() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let mut quorum = req_map.new_quorum(0..3).unwrap();
+        quorum.wait(4, None).ok();
+    }
+}
@@ -0,0 +1,379 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+type Entry = Arc<dyn Any + Send + Sync>;
+
+/// how the ids of a registry are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Space {
+    /// 64 bit ids, 32 bit slot index and 32 bit generation, the slot is
+    /// retired once all its generations are used
+    Wide,
+    /// 32 bit ids, 16 bit slot index and 16 bit generation, the generation
+    /// wraps around so the slots are never used up
+    Compact,
+}
+
+impl Space {
+    /// number of bits in an id
+    const fn id_bits(self) -> u32 {
+        match self {
+            Space::Wide => 64,
+            Space::Compact => 32,
+        }
+    }
+
+    /// number of low bits in an id that hold the slot index
+    const fn index_bits(self) -> u32 {
+        self.id_bits() / 2
+    }
+
+    fn id_mask(self) -> u64 {
+        u64::MAX >> (64 - self.id_bits())
+    }
+
+    fn index_mask(self) -> u64 {
+        (1 << self.index_bits()) - 1
+    }
+
+    /// the generation part of an id, it's never zero
+    fn gen_mask(self) -> u64 {
+        self.id_mask() >> self.index_bits()
+    }
+}
+
+/// why a slot was released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Release {
+    /// the entry was taken by the id
+    Taken,
+    /// the id was canceled by the owner
+    Canceled,
+    /// the owner was dropped
+    Dropped,
+}
+
+/// why an id missed the registry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Miss {
+    /// the id was never handed out, or it's of another type
+    Unknown,
+    /// the id is from the last generation of the slot
+    Released(Release),
+    /// the id is from an older generation
+    Stale,
+}
+
+struct Slot {
+    gen: u64,
+    entry: Option<Entry>,
+    // how the last generation was released
+    released: Option<Release>,
+}
+
+/// one shard of the global slot registry that maps ids to armed entries
+///
+/// an id is made of the slot index and the slot generation, the generation
+/// is bumped every time the slot is released, so a stale or duplicated id
+/// would not match the slot again, and a forged id is just a miss
+///
+/// the low bits of the slot index pick the shard, so the lookups of
+/// different ids rarely contend on the same lock, the released slots are
+/// reused in order, so the generations of all the slots advance evenly
+struct Registry {
+    slots: Vec<Slot>,
+    free: VecDeque<usize>,
+}
+
+/// the key of an owner that is registering, it's never handed out as an id,
+/// so an owner could claim its key with a compare and swap before registering
+pub(crate) const ARMING: u64 = u64::MAX;
+
+/// number of the registry shards of each space
+const SHARDS: usize = 16;
+
+static REGISTRY: [Mutex<Registry>; SHARDS] = [const { Mutex::new(Registry::new()) }; SHARDS];
+static COMPACT_REGISTRY: [Mutex<Registry>; SHARDS] =
+    [const { Mutex::new(Registry::new()) }; SHARDS];
+
+fn shard(space: Space, shard: usize) -> MutexGuard<'static, Registry> {
+    let shards = match space {
+        Space::Wide => &REGISTRY,
+        Space::Compact => &COMPACT_REGISTRY,
+    };
+    // we never panic with the lock held, but be tolerant anyway
+    shards[shard].lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// the shard that holds the slot of the id
+fn registry(space: Space, id: u64) -> MutexGuard<'static, Registry> {
+    shard(space, decode(space, id).0 % SHARDS)
+}
+
+/// odd multiplier of the round function
+const MIX: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// number of the feistel rounds, every bit of the id depends on every bit
+/// of the slot index and generation after four rounds
+const ROUNDS: usize = 4;
+
+/// the per process round keys that the ids are permuted with
+fn secret() -> [u64; ROUNDS] {
+    static SECRET: OnceLock<[u64; ROUNDS]> = OnceLock::new();
+    *SECRET.get_or_init(|| {
+        let state = RandomState::new();
+        std::array::from_fn(|i| state.hash_one(i))
+    })
+}
+
+// the keyed round function, the result is as wide as a half of the id
+fn round(space: Space, half: u64, key: u64) -> u64 {
+    let x = (half ^ key).wrapping_mul(MIX);
+    (x ^ (x >> 29)).wrapping_mul(MIX) >> (64 - space.index_bits())
+}
+
+/// permute the slot index and generation into an opaque id with a keyed
+/// feistel network, so the ids handed out reveal neither the registry
+/// layout nor the other ids
+fn encode(space: Space, index: usize, gen: u64) -> u64 {
+    let (mut left, mut right) = (gen, index as u64);
+    for key in secret() {
+        (left, right) = (right, left ^ round(space, right, key));
+    }
+    (left << space.index_bits()) | right
+}
+
+fn decode(space: Space, id: u64) -> (usize, u64) {
+    let (mut left, mut right) = (id >> space.index_bits(), id & space.index_mask());
+    for key in secret().into_iter().rev() {
+        (left, right) = (right ^ round(space, left, key), left);
+    }
+    (right as usize, left)
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Registry {
+            slots: Vec::new(),
+            free: VecDeque::new(),
+        }
+    }
+
+    fn slot(&mut self, space: Space, id: u64) -> Result<&mut Slot, Miss> {
+        if id & !space.id_mask() != 0 {
+            return Err(Miss::Unknown);
+        }
+        let (index, gen) = decode(space, id);
+        let slot = match self.slots.get_mut(index / SHARDS) {
+            Some(slot) if gen != 0 => slot,
+            _ => return Err(Miss::Unknown),
+        };
+        if slot.gen == gen {
+            return match slot.entry.is_some() {
+                true => Ok(slot),
+                false => Err(Miss::Unknown),
+            };
+        }
+        let last_gen = match slot.gen - 1 {
+            0 => space.gen_mask(),
+            gen => gen,
+        };
+        match slot.released {
+            Some(release) if last_gen == gen => Err(Miss::Released(release)),
+            _ => Err(Miss::Stale),
+        }
+    }
+
+    fn release(&mut self, space: Space, id: u64, release: Release) -> Option<Entry> {
+        let local = decode(space, id).0 / SHARDS;
+        let slot = &mut self.slots[local];
+        let entry = slot.entry.take();
+        slot.released = Some(release);
+        slot.gen += 1;
+        match space {
+            // never wrap the generation, retire the slot after the last one
+            Space::Wide if slot.gen > space.gen_mask() => return entry,
+            // wrap around and skip zero
+            Space::Compact if slot.gen > space.gen_mask() => slot.gen = 1,
+            _ => {}
+        }
+        self.free.push_back(local);
+        entry
+    }
+
+    // arm a slot of the `shard`th shard
+    fn register(&mut self, space: Space, shard: usize, entry: Entry) -> Option<u64> {
+        loop {
+            let local = match self.free.pop_front() {
+                Some(local) => local,
+                None => {
+                    let local = self.slots.len();
+                    if (local * SHARDS + shard) as u64 > space.index_mask() {
+                        return None;
+                    }
+                    self.slots.push(Slot {
+                        gen: 1,
+                        entry: None,
+                        released: None,
+                    });
+                    local
+                }
+            };
+            let id = encode(space, local * SHARDS + shard, self.slots[local].gen);
+            // zero and `ARMING` are never valid ids, skip the generation
+            if id == 0 || id == ARMING {
+                self.release(space, id, Release::Taken);
+                continue;
+            }
+            self.slots[local].entry = Some(entry);
+            return Some(id);
+        }
+    }
+
+    fn take<E: Any + Send + Sync>(&mut self, space: Space, id: u64) -> Result<Arc<E>, Miss> {
+        let slot = self.slot(space, id)?;
+        if !slot.entry.as_ref().is_some_and(|e| e.is::<E>()) {
+            return Err(Miss::Unknown);
+        }
+        let entry = self.release(space, id, Release::Taken);
+        entry
+            .ok_or(Miss::Unknown)?
+            .downcast()
+            .map_err(|_| Miss::Unknown)
+    }
+
+    fn get<E: Any + Send + Sync>(&mut self, space: Space, id: u64) -> Result<Arc<E>, Miss> {
+        let entry = self.slot(space, id)?.entry.clone().ok_or(Miss::Unknown)?;
+        entry.downcast().map_err(|_| Miss::Unknown)
+    }
+
+    fn remove(&mut self, space: Space, id: u64, release: Release) -> bool {
+        if self.slot(space, id).is_err() {
+            return false;
+        }
+        self.release(space, id, release).is_some()
+    }
+}
+
+/// arm a new slot with the entry, return `None` if all slots are in use
+///
+/// the shards are taken in turn, a full shard passes the entry to the next
+pub(crate) fn register(space: Space, entry: Entry) -> Option<u64> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let first = NEXT.fetch_add(1, Ordering::Relaxed);
+    (0..SHARDS)
+        .map(|i| (first + i) % SHARDS)
+        .find_map(|i| shard(space, i).register(space, i, entry.clone()))
+}
+
+/// take the entry out of the registry and release the slot
+///
+/// returns the `Miss` if the id doesn't match an armed slot or the entry is
+/// not an `E`, in which case the slot is left untouched
+pub(crate) fn take<E: Any + Send + Sync>(space: Space, id: u64) -> Result<Arc<E>, Miss> {
+    registry(space, id).take(space, id)
+}
+
+/// get a ref of the entry and leave the slot armed
+pub(crate) fn get<E: Any + Send + Sync>(space: Space, id: u64) -> Result<Arc<E>, Miss> {
+    registry(space, id).get(space, id)
+}
+
+/// release the slot if the id still matches, return true if released
+pub(crate) fn remove(space: Space, id: u64, release: Release) -> bool {
+    registry(space, id).remove(space, id, release)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn id_mixing() {
+        for space in [Space::Wide, Space::Compact] {
+            let (index_mask, gen_mask) = (space.index_mask(), space.gen_mask());
+            for (index, gen) in [(0, 1), (1, 1), (index_mask as usize, gen_mask), (42, 7)] {
+                let id = encode(space, index, gen);
+                assert_eq!(id & !space.id_mask(), 0);
+                assert_eq!(decode(space, id), (index, gen));
+            }
+        }
+    }
+
+    #[test]
+    fn id_opaque() {
+        for space in [Space::Wide, Space::Compact] {
+            // the generations of a slot spread over all the bits of the ids
+            let ids: Vec<_> = (1..=64).map(|gen| encode(space, 5, gen)).collect();
+            let low: HashSet<_> = ids.iter().map(|id| id & space.index_mask()).collect();
+            let high: HashSet<_> = ids.iter().map(|id| id >> space.index_bits()).collect();
+            assert!(low.len() > 48 && high.len() > 48);
+        }
+    }
+
+    #[test]
+    fn retire_slot() {
+        let space = Space::Wide;
+        let mut reg = Registry::new();
+        let id = reg.register(space, 3, Arc::new(1usize)).unwrap();
+        assert_eq!(decode(space, id).0, 3);
+        // fast forward to the last generation
+        reg.release(space, id, Release::Taken);
+        reg.slots[0].gen = space.gen_mask();
+        let last = reg.register(space, 3, Arc::new(2usize)).unwrap();
+        assert_eq!(reg.take::<usize>(space, last).map(|e| *e), Ok(2));
+
+        // the slot is retired instead of wrapping to an old generation
+        let id = reg.register(space, 3, Arc::new(3usize)).unwrap();
+        assert_eq!(decode(space, id).0, SHARDS + 3);
+        let err = reg.take::<usize>(space, last);
+        assert_eq!(err, Err(Miss::Released(Release::Taken)));
+        let old = encode(space, 3, 1);
+        assert_eq!(reg.take::<usize>(space, old), Err(Miss::Stale));
+    }
+
+    #[test]
+    fn compact_wrap() {
+        let space = Space::Compact;
+        let mut reg = Registry::new();
+        let id = reg.register(space, 0, Arc::new(1usize)).unwrap();
+        // fast forward to the last generation
+        reg.release(space, id, Release::Taken);
+        reg.slots[0].gen = space.gen_mask();
+        let last = reg.register(space, 0, Arc::new(2usize)).unwrap();
+        assert!(last <= u64::from(u32::MAX));
+        assert_eq!(reg.take::<usize>(space, last).map(|e| *e), Ok(2));
+
+        // the generation wraps around and the slot is reused
+        let id = reg.register(space, 0, Arc::new(3usize)).unwrap();
+        assert_eq!(decode(space, id), (0, 1));
+        let err = reg.take::<usize>(space, last);
+        assert_eq!(err, Err(Miss::Released(Release::Taken)));
+        // an id wider than 32 bits is never valid
+        let err = reg.take::<usize>(space, id | 1 << 32);
+        assert_eq!(err, Err(Miss::Unknown));
+    }
+
+    #[test]
+    fn shard_full() {
+        let space = Space::Compact;
+        // fill up a shard of the global registry
+        let mut ids = Vec::new();
+        while let Some(id) = shard(space, 0).register(space, 0, Arc::new(0usize)) {
+            ids.push(id);
+        }
+        assert!(ids.len() <= (space.index_mask() as usize + 1) / SHARDS);
+        // the entries are passed to the other shards
+        for i in 0..SHARDS {
+            let id = register(space, Arc::new(i)).unwrap();
+            assert_ne!(decode(space, id).0 % SHARDS, 0);
+            assert_eq!(take::<usize>(space, id).map(|e| *e), Ok(i));
+        }
+        ids.into_iter()
+            .for_each(|id| assert!(remove(space, id, Release::Dropped)));
+    }
+}
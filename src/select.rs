@@ -0,0 +1,227 @@
+use may::coroutine::{self, ParkError};
+use may::sync::Blocker;
+
+use crate::error::WaitError;
+use crate::waiter::Waiter;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub(crate) mod sealed {
+    use crate::waiter::Waiter;
+
+    pub trait AsWaiter<T> {
+        fn waiter(&self) -> &Waiter<T>;
+        // the debug format of the key used in errors
+        fn key(&self) -> Option<String>;
+        // cancel the wait, the rsp that comes later is not delivered
+        fn cancel(&self) {
+            self.waiter().cancel_wait();
+        }
+    }
+}
+
+/// waiters that could be selected by `wait_any`
+///
+/// it's implemented for `WaiterGuard`, `OwnedWaiterGuard`, `TokenWaiter`
+/// and `Receiver`
+pub trait Waitable<T>: sealed::AsWaiter<T> {}
+
+impl<T, W: sealed::AsWaiter<T>> Waitable<T> for W {}
+
+/// what to do with the other waiters once `wait_any` got a rsp
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Others {
+    /// leave them registered, they could be waited again
+    Keep,
+    /// cancel and unregister them, their wait would return
+    /// `WaitError::Canceled` and a late rsp is not delivered
+    Cancel,
+}
+
+/// wait for the first rsp of a group of waiters with one park
+///
+/// return the index of the winner and its rsp, a waiter that failed is
+/// skipped, if all of them failed the last error is returned
+pub fn wait_any<T, D: Into<Option<Duration>>>(
+    waiters: &[&dyn Waitable<T>],
+    timeout: D,
+    others: Others,
+) -> Result<(usize, T), WaitError> {
+    assert!(!waiters.is_empty(), "no waiter to wait");
+    let watcher = Watcher::new(waiters.iter().map(|w| w.waiter()));
+    let deadline = timeout.into().and_then(|t| watcher.start.checked_add(t));
+
+    let ret = loop {
+        match poll_any(waiters) {
+            Some(ret) => break ret,
+            None => {
+                if let Err(e) = watcher.park(deadline) {
+                    break Err(e);
+                }
+            }
+        }
+    };
+
+    drop(watcher);
+    if let (Ok((index, _)), Others::Cancel) = (&ret, others) {
+        waiters
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i != index)
+            .for_each(|(_, w)| w.cancel());
+    }
+    ret
+}
+
+/// one blocker that watches a group of waiters
+///
+/// it's unparked when any of the waiters is triggered, and is removed from
+/// the waiters when dropped
+pub(crate) struct Watcher<'a, T> {
+    blocker: Arc<Blocker>,
+    waiters: Vec<&'a Waiter<T>>,
+    pub start: Instant,
+}
+
+impl<'a, T> Watcher<'a, T> {
+    pub fn new(waiters: impl Iterator<Item = &'a Waiter<T>>) -> Self {
+        let blocker = Blocker::current();
+        let waiters: Vec<_> = waiters.collect();
+        waiters.iter().for_each(|w| w.watch(Some(blocker.clone())));
+        Watcher {
+            blocker,
+            waiters,
+            start: Instant::now(),
+        }
+    }
+
+    /// park until any of the waiters is triggered or the deadline is reached
+    ///
+    /// it may return `Ok` for a false wake up, so always poll the waiters
+    pub fn park(&self, deadline: Option<Instant>) -> Result<(), WaitError> {
+        let remain = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        match self.blocker.park(remain) {
+            Ok(_) => Ok(()),
+            Err(ParkError::Timeout) => Err(WaitError::Timeout {
+                key: None,
+                elapsed: self.start.elapsed(),
+            }),
+            // the watcher is removed from the waiters when unwinding
+            Err(ParkError::Canceled) => coroutine::trigger_cancel_panic(),
+        }
+    }
+}
+
+impl<'a, T> Drop for Watcher<'a, T> {
+    fn drop(&mut self) {
+        self.waiters.iter().for_each(|w| w.watch(None));
+    }
+}
+
+// return `None` if no rsp yet and not all the waiters failed
+fn poll_any<T>(waiters: &[&dyn Waitable<T>]) -> Option<Result<(usize, T), WaitError>> {
+    let mut err = None;
+    let mut pending = false;
+    for (i, w) in waiters.iter().enumerate() {
+        match w.waiter().try_rsp() {
+            Some(Ok(rsp)) => return Some(Ok((i, rsp))),
+            Some(Err(e)) => err = Some(with_key(e, *w)),
+            None => pending = true,
+        }
+    }
+    match pending {
+        true => None,
+        false => err.map(Err),
+    }
+}
+
+fn with_key<T>(e: WaitError, w: &dyn Waitable<T>) -> WaitError {
+    match w.key() {
+        Some(key) => e.with_key(key),
+        None => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TokenWaiter, WaiterMap};
+    use may::go;
+    use std::sync::Arc;
+
+    #[test]
+    fn wait_any_keep() {
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let rmap = req_map.clone();
+        go!(move || {
+            let w1 = rmap.new_waiter(1);
+            let w2 = rmap.new_waiter(2);
+            let token = TokenWaiter::<usize>::new();
+            let id = token.id().unwrap();
+            go!(move || TokenWaiter::set_rsp(id, 42usize));
+
+            let ret = wait_any(&[&w1, &w2, &token], None, Others::Keep);
+            assert_eq!(ret.unwrap(), (2, 42));
+
+            // the others are still registered
+            rmap.set_rsp(&2, 100).unwrap();
+            let ret = wait_any(&[&w1, &w2], None, Others::Keep);
+            assert_eq!(ret.unwrap(), (1, 100));
+            assert_eq!(
+                w2.wait_rsp(Duration::from_millis(10)).unwrap_err().key(),
+                Some("2")
+            );
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn wait_any_cancel() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let w1 = req_map.new_waiter(1);
+        let w2 = req_map.new_waiter(2);
+        let token = TokenWaiter::<usize>::new();
+        let id: u64 = token.id().unwrap().into();
+
+        req_map.set_rsp(&1, 10).unwrap();
+        let ret = wait_any(&[&w1, &w2, &token], None, Others::Cancel);
+        assert_eq!(ret.unwrap(), (0, 10));
+        assert_eq!(
+            w2.wait_rsp(None),
+            Err(WaitError::Canceled {
+                key: Some("2".into())
+            })
+        );
+        // the canceled guard is unregistered, the late rsp is given back
+        assert_eq!(req_map.set_rsp(&2, 2), Err(2));
+        assert!(w2.wait_rsp(Duration::from_millis(10)).is_err());
+        // the canceled token waiter drops the late rsp and could be used again
+        let err = TokenWaiter::set_rsp(id.into(), 1usize).unwrap_err();
+        assert!(!err.is_consumed());
+        assert!(token.wait_rsp(None).is_err());
+        let id = token.id().unwrap();
+        TokenWaiter::set_rsp(id, 2usize).unwrap();
+        assert_eq!(token.wait_rsp(None).unwrap(), 2);
+    }
+
+    #[test]
+    fn wait_any_timeout() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let w1 = req_map.new_waiter(1);
+        let w2 = req_map.new_waiter(2);
+        let ret = wait_any(&[&w1, &w2], Duration::from_millis(50), Others::Keep);
+        assert!(ret.unwrap_err().is_timeout());
+
+        // all failed
+        req_map.cancel_all();
+        let ret = wait_any(&[&w1, &w2], None, Others::Keep);
+        assert_eq!(
+            ret.unwrap_err(),
+            WaitError::Canceled {
+                key: Some("2".into())
+            }
+        );
+    }
+}
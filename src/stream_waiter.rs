@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::error::WaitError;
+use crate::id::ID;
+use crate::registry::{self, Miss, Release, Space, ARMING};
+use crate::token_waiter::{Error, RspError};
+use crate::waiter::Waiter;
+
+/// how a `StreamWaiter` keeps the rsps that are not received yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamMode {
+    /// queue all the rsps in order
+    Queue,
+    /// keep only the latest rsp
+    Latest,
+}
+
+// the rsps not received yet, and how the stream is closed
+struct Queue<T> {
+    rsps: VecDeque<T>,
+    closed: Option<Release>,
+}
+
+// the part that is shared with the registry while the id is armed
+struct Inner<T> {
+    // rung every time a rsp arrives or the stream is closed
+    doorbell: Waiter<()>,
+    // the rsps are never pushed after the stream is closed
+    queue: Mutex<Queue<T>>,
+    mode: StreamMode,
+    key: AtomicU64,
+}
+
+impl<T> Inner<T> {
+    fn queue(&self) -> MutexGuard<'_, Queue<T>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_closed(&self) -> bool {
+        self.queue().closed.is_some()
+    }
+
+    fn push(&self, id: u64, rsp: T) -> Result<(), RspError<T>>
+    where
+        T: Send + 'static,
+    {
+        let mut queue = self.queue();
+        // the stream may be closed after the id is looked up
+        if let Some(release) = queue.closed {
+            drop(queue);
+            return Err(RspError::dead_letter(id, rsp, Miss::Released(release)));
+        }
+        if self.mode == StreamMode::Latest {
+            queue.rsps.clear();
+        }
+        queue.rsps.push_back(rsp);
+        drop(queue);
+        self.doorbell.set_rsp(());
+        Ok(())
+    }
+
+    fn close(&self, release: Release) {
+        self.queue().closed.get_or_insert(release);
+        self.doorbell.set_rsp(());
+    }
+}
+
+/// token waiter whose id stays valid for a stream of rsps
+///
+/// the responder sends any number of rsps with `send` and ends the stream
+/// with `finish`, the waiter could also end it with `close`, after that the
+/// rsps are given back with the reason like `TokenWaiter::set_rsp`
+pub struct StreamWaiter<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> StreamWaiter<T> {
+    pub fn new(mode: StreamMode) -> Self {
+        StreamWaiter {
+            inner: Arc::new(Inner {
+                doorbell: Waiter::new(),
+                queue: Mutex::new(Queue {
+                    rsps: VecDeque::new(),
+                    closed: None,
+                }),
+                mode,
+                key: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// get the id of the stream, it's the same one until the stream is closed
+    pub fn id(&self) -> Result<ID<T>, Error>
+    where
+        T: Send + 'static,
+    {
+        // claim the key, so only one caller registers the stream
+        loop {
+            if self.inner.is_closed() {
+                return Err(Error::Closed);
+            }
+            let key = &self.inner.key;
+            match key.compare_exchange(0, ARMING, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                // another caller is registering it
+                Err(ARMING) => std::hint::spin_loop(),
+                Err(id) => return Ok(ID::from(id)),
+            }
+        }
+
+        // the registry holds a ref of the stream until it's closed
+        let id = match registry::register(Space::Wide, self.inner.clone()) {
+            Some(id) => id,
+            None => {
+                self.inner.key.store(0, Ordering::Release);
+                return Err(Error::Exhausted);
+            }
+        };
+        // the stream may be closed meanwhile, don't leave the id armed
+        let key = &self.inner.key;
+        let published = key.compare_exchange(ARMING, id, Ordering::AcqRel, Ordering::Acquire);
+        if published.is_err() || self.inner.is_closed() {
+            let _ = key.compare_exchange(id, 0, Ordering::AcqRel, Ordering::Acquire);
+            registry::remove(Space::Wide, id, Release::Canceled);
+            return Err(Error::Closed);
+        }
+        Ok(ID::from(id))
+    }
+
+    /// receive the next rsp, `None` means the stream is closed and all the
+    /// rsps are received
+    pub fn recv<D: Into<Option<Duration>>>(&self, timeout: D) -> Result<Option<T>, WaitError> {
+        let deadline = timeout.into().and_then(|t| Instant::now().checked_add(t));
+        self.recv_deadline(deadline)
+    }
+
+    /// receive the next rsp until the deadline
+    pub fn recv_until(&self, deadline: Instant) -> Result<Option<T>, WaitError> {
+        self.recv_deadline(Some(deadline))
+    }
+
+    fn recv_deadline(&self, deadline: Option<Instant>) -> Result<Option<T>, WaitError> {
+        loop {
+            let mut queue = self.inner.queue();
+            if let Some(rsp) = queue.rsps.pop_front() {
+                return Ok(Some(rsp));
+            }
+            if queue.closed.is_some() {
+                return Ok(None);
+            }
+            drop(queue);
+            let ret = match deadline {
+                Some(deadline) => self.inner.doorbell.wait_rsp_until(deadline),
+                None => self.inner.doorbell.wait_rsp(None),
+            };
+            ret.map_err(|e| self.with_key(e))?;
+        }
+    }
+
+    /// iterate the rsps until the stream is closed
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.recv(None).ok().flatten())
+    }
+
+    /// return true if the stream is closed by either side
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// close the stream from the waiter side, the rsps already arrived could
+    /// still be received
+    pub fn close(&self) {
+        // close it first, so a racing `id` would not arm it again
+        self.inner.close(Release::Canceled);
+        let id = self.inner.key.swap(0, Ordering::AcqRel);
+        if id != 0 && id != ARMING {
+            registry::remove(Space::Wide, id, Release::Canceled);
+        }
+    }
+
+    fn with_key(&self, e: WaitError) -> WaitError {
+        match self.inner.key.load(Ordering::Acquire) {
+            0 | ARMING => e,
+            id => e.with_key(ID::<T>::from(id).to_string()),
+        }
+    }
+
+    /// send a rsp to the stream with id, the id is still valid after that
+    ///
+    /// a forged or stale `id`, or a closed stream, is passed to the dead
+    /// letter handler of `TokenWaiter<T>` and the rsp is given back
+    pub fn send(id: &ID<T>, rsp: T) -> Result<(), RspError<T>>
+    where
+        T: Send + 'static,
+    {
+        match registry::get::<Inner<T>>(Space::Wide, id.0) {
+            Ok(inner) => inner.push(id.0, rsp),
+            Err(miss) => Err(RspError::dead_letter(id.0, rsp, miss)),
+        }
+    }
+
+    /// close the stream from the responder side, return false if the stream
+    /// is already closed
+    pub fn finish(id: ID<T>) -> bool
+    where
+        T: Send + 'static,
+    {
+        match registry::take::<Inner<T>>(Space::Wide, id.0) {
+            Ok(inner) => {
+                inner.key.store(0, Ordering::Release);
+                inner.close(Release::Taken);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl<T> fmt::Debug for StreamWaiter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StreamWaiter{{ ... }}")
+    }
+}
+
+impl<T> Drop for StreamWaiter<T> {
+    fn drop(&mut self) {
+        // disarm the id, the later rsps are given back
+        let id = self.inner.key.load(Ordering::Acquire);
+        if id != 0 {
+            registry::remove(Space::Wide, id, Release::Dropped);
+        }
+        self.inner.close(Release::Dropped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeadLetterReason;
+    use may::go;
+
+    #[test]
+    fn stream_queue() {
+        let waiter = StreamWaiter::<usize>::new(StreamMode::Queue);
+        let id = waiter.id().unwrap();
+        go!(move || {
+            for i in 0..10 {
+                StreamWaiter::send(&id, i).unwrap();
+            }
+            assert!(StreamWaiter::finish(id));
+        });
+        assert_eq!(
+            waiter.iter().collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+        assert!(waiter.is_closed());
+        assert_eq!(waiter.recv(None), Ok(None));
+        assert_eq!(waiter.id().unwrap_err(), Error::Closed);
+    }
+
+    #[test]
+    fn stream_latest() {
+        let waiter = StreamWaiter::<usize>::new(StreamMode::Latest);
+        let id = waiter.id().unwrap();
+        // the id stays the same
+        assert_eq!(u64::from(waiter.id().unwrap()), id.0);
+        for i in 0..3 {
+            StreamWaiter::send(&id, i).unwrap();
+        }
+        assert_eq!(waiter.recv(None), Ok(Some(2)));
+        let err = waiter.recv(Duration::from_millis(10)).unwrap_err();
+        assert!(err.is_timeout());
+        assert_eq!(err.key(), Some(id.to_string().as_str()));
+    }
+
+    #[test]
+    fn stream_close() {
+        let waiter = StreamWaiter::<usize>::new(StreamMode::Queue);
+        let id = waiter.id().unwrap();
+        let raw = u64::from(waiter.id().unwrap());
+        StreamWaiter::send(&id, 1).unwrap();
+        waiter.close();
+        let err = StreamWaiter::send(&id, 2).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::Canceled);
+        assert!(!StreamWaiter::finish(id));
+        // the rsp arrived before closing is still there
+        assert_eq!(waiter.recv(None), Ok(Some(1)));
+        assert_eq!(waiter.recv(None), Ok(None));
+
+        // finished by the responder
+        let waiter = StreamWaiter::<usize>::new(StreamMode::Queue);
+        let id = waiter.id().unwrap();
+        let raw2 = u64::from(waiter.id().unwrap());
+        assert_ne!(raw, raw2);
+        assert!(StreamWaiter::finish(id));
+        let err = StreamWaiter::send(&ID::from(raw2), 3).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::Consumed);
+        assert_eq!(waiter.recv(None), Ok(None));
+    }
+
+    #[test]
+    fn stream_close_race() {
+        let waiter = StreamWaiter::<usize>::new(StreamMode::Queue);
+        let id = waiter.id().unwrap();
+        // `send` looks up the stream right before it's closed
+        let inner = registry::get::<Inner<usize>>(Space::Wide, id.0).unwrap();
+        waiter.close();
+        assert_eq!(waiter.recv(None), Ok(None));
+        let err = inner.push(id.0, 1).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::Canceled);
+        assert_eq!(waiter.recv(None), Ok(None));
+
+        // finished by the responder
+        let waiter = StreamWaiter::<usize>::new(StreamMode::Queue);
+        let id = waiter.id().unwrap();
+        let inner = registry::get::<Inner<usize>>(Space::Wide, id.0).unwrap();
+        assert!(StreamWaiter::<usize>::finish(ID::from(id.0)));
+        let err = inner.push(id.0, 2).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::Consumed);
+        assert_eq!(waiter.recv(None), Ok(None));
+    }
+
+    #[test]
+    fn stream_id_race() {
+        let waiter = StreamWaiter::<usize>::new(StreamMode::Queue);
+        // the racing callers all get the same id
+        let ids: Vec<_> = std::thread::scope(|s| {
+            let ids: Vec<_> = (0..8).map(|_| s.spawn(|| waiter.id().unwrap())).collect();
+            ids.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert!(ids.iter().all(|id| id.0 == ids[0].0));
+        StreamWaiter::send(&ids[0], 1).unwrap();
+        assert_eq!(waiter.recv(None), Ok(Some(1)));
+        waiter.close();
+        assert_eq!(waiter.id().unwrap_err(), Error::Closed);
+    }
+}
@@ -1,106 +1,338 @@
-use std::cell::Cell;
 use std::fmt;
-use std::io;
-use std::marker::PhantomPinned;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::dead_letter::{self, DeadLetterReason, DeadLetterStats};
+use crate::error::WaitError;
+use crate::id::{ID, ID32};
+use crate::registry::{self, Miss, Release, Space, ARMING};
+use crate::select::sealed::AsWaiter;
+#[cfg(feature = "async")]
+use crate::wait_future::WaitRsp;
 use crate::waiter::Waiter;
 
-thread_local! {static TAG: Cell<usize> = Cell::new(0);}
+/// the id error, it's exported as `IdError`
+///
+/// ```
+/// use co_waiter::{IdError, TokenWaiter};
+///
+/// let waiter = TokenWaiter::<usize>::new();
+/// let _id = waiter.id().unwrap();
+/// assert!(matches!(waiter.id(), Err(IdError::InUse)));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Error {
+    /// the previous id is not consumed yet
+    InUse,
+    /// all the id slots are in use
+    Exhausted,
+    /// the raw value is never a valid id
+    Invalid,
+    /// the stream is closed
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InUse => write!(f, "the previous id is not consumed yet"),
+            Error::Exhausted => write!(f, "all the id slots are in use"),
+            Error::Invalid => write!(f, "not a valid id"),
+            Error::Closed => write!(f, "the stream is closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// the rsp that `TokenWaiter::set_rsp` could not deliver
+///
+/// `reason` tells a stale id, whose waiter is gone or canceled, from an id
+/// that is already consumed by an earlier rsp
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RspError<T> {
+    rsp: T,
+    reason: DeadLetterReason,
+}
 
-/// the id type from `TokenWaiter::get_id()`
-#[derive(Debug)]
-pub struct ID(usize);
+impl<T> RspError<T> {
+    // pass the rsp that missed the registry to the dead letter handler
+    pub(crate) fn dead_letter(id: u64, rsp: T, miss: Miss) -> Self
+    where
+        T: Send + 'static,
+    {
+        let reason = match miss {
+            Miss::Unknown => DeadLetterReason::NeverRegistered,
+            Miss::Released(Release::Taken) => DeadLetterReason::Consumed,
+            Miss::Released(Release::Canceled) => DeadLetterReason::Canceled,
+            // the waiter is gone, or the id is from an older round
+            Miss::Released(Release::Dropped) | Miss::Stale => DeadLetterReason::TimedOut,
+        };
+        dead_letter::handle_token(id, &rsp, reason);
+        RspError { rsp, reason }
+    }
+
+    /// why the rsp is not delivered
+    pub fn reason(&self) -> DeadLetterReason {
+        self.reason
+    }
+
+    /// return true if the id already got its rsp
+    pub fn is_consumed(&self) -> bool {
+        self.reason == DeadLetterReason::Consumed
+    }
+
+    /// give back the rsp
+    pub fn into_rsp(self) -> T {
+        self.rsp
+    }
+}
 
-impl From<usize> for ID {
-    fn from(id: usize) -> Self {
-        ID(id)
+impl<T> fmt::Debug for RspError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RspError")
+            .field("reason", &self.reason)
+            .finish_non_exhaustive()
     }
 }
 
-impl From<ID> for usize {
-    fn from(id: ID) -> Self {
-        id.0
+impl<T> fmt::Display for RspError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rsp not delivered, the id is {}", self.reason)
     }
 }
 
-/// get id error
-#[derive(Debug)]
-pub struct Error;
+impl<T> std::error::Error for RspError<T> {}
+
+// the part that is shared with the registry while the id is armed
+struct Inner<T> {
+    waiter: Waiter<T>,
+    key: AtomicU64,
+    // the key is a compact id
+    compact: AtomicBool,
+}
+
+impl<T> Inner<T> {
+    fn space(&self) -> Space {
+        match self.compact.load(Ordering::Acquire) {
+            true => Space::Compact,
+            false => Space::Wide,
+        }
+    }
+
+    // deliver the rsp after the id is taken out of the registry
+    fn deliver(&self, rsp: T) {
+        // the rsp is stored before the id is cleared, so the next round that
+        // sees the cleared id always finds the rsp and drops it
+        self.waiter.put_rsp(rsp);
+        // clear the id so that we can get the id again
+        self.key.store(0, Ordering::Release);
+        // wake up the blocker
+        self.waiter.wake();
+    }
+}
 
 /// token waiter that could be used for primitive wait blocking
+///
+/// it's fine to drop the waiter while its id is outstanding, a racing
+/// `set_rsp` either delivers the rsp to the shared waiter state before the
+/// drop, or gets the rsp back with `DeadLetterReason::TimedOut`
+///
+/// the waiter state is shared with the id on the heap, so the waiter could
+/// be moved freely while the id is outstanding
 pub struct TokenWaiter<T> {
-    waiter: Waiter<T>,
-    key: AtomicUsize,
-    _phantom: PhantomPinned,
+    inner: Arc<Inner<T>>,
 }
 
 impl<T> TokenWaiter<T> {
     pub fn new() -> Self {
         TokenWaiter {
-            key: AtomicUsize::new(0),
-            waiter: Waiter::new(),
-            _phantom: PhantomPinned,
+            inner: Arc::new(Inner {
+                key: AtomicU64::new(0),
+                compact: AtomicBool::new(false),
+                waiter: Waiter::new(),
+            }),
         }
     }
 
     /// get the id of this token_waiter
     /// if the waiter is not triggered, we can't get id again
-    pub fn id(&self) -> Result<ID, Error> {
-        let id = self.key.load(Ordering::Relaxed);
-        if id != 0 {
+    pub fn id(&self) -> Result<ID<T>, Error>
+    where
+        T: Send + 'static,
+    {
+        self.arm(Space::Wide).map(ID::from)
+    }
+
+    /// get the compact id of this token_waiter, see `ID32`
+    /// if the waiter is not triggered, we can't get id again
+    pub fn id32(&self) -> Result<ID32<T>, Error>
+    where
+        T: Send + 'static,
+    {
+        let id = self.arm(Space::Compact)?;
+        // the compact ids are never zero and fit in 32 bits
+        ID32::try_from(id as u32)
+    }
+
+    fn arm(&self, space: Space) -> Result<u64, Error>
+    where
+        T: Send + 'static,
+    {
+        // claim the key, so only one caller registers the waiter
+        let claim = self
+            .inner
+            .key
+            .compare_exchange(0, ARMING, Ordering::AcqRel, Ordering::Acquire);
+        if claim.is_err() {
             // the id is already initialized
-            return Err(Error);
+            return Err(Error::InUse);
         }
 
-        // pin address is never changed
-        let address = self as *const _ as usize;
-        let tag = TAG.with(|t| {
-            let x = t.get();
-            t.set(x + 1);
-            (x & 0x1f) << 1
-        });
+        // the waiter may be canceled last round, with a late rsp left behind
+        self.inner.waiter.reset();
+        // the registry holds a ref of the waiter until the id is consumed
+        let id = match registry::register(space, self.inner.clone()) {
+            Some(id) => id,
+            None => {
+                self.inner.key.store(0, Ordering::Release);
+                return Err(Error::Exhausted);
+            }
+        };
+        self.inner
+            .compact
+            .store(space == Space::Compact, Ordering::Release);
+        self.inner.key.store(id, Ordering::Release);
+        Ok(id)
+    }
 
-        let id = (address << 3) | tag;
-        self.key.store(id, Ordering::Relaxed);
-        Ok(ID(id))
-    }
-
-    // make sure the id valid one from get id
-    fn from_id(id: &ID) -> Option<&Self> {
-        let id = id.0;
-        // TODO: how to check if the address is valid?
-        // if the id is wrong enough we could get a SIGSEGV
-        let address = (id >> 3) & !0x7;
-        let waiter = unsafe { &*(address as *const Self) };
-        // need to check if the memory is still valid
-        // lock the key to protect contention with drop
-        if waiter
-            .key
-            .compare_exchange(id, id + 1, Ordering::AcqRel, Ordering::Relaxed)
-            .is_ok()
-        {
-            Some(waiter)
-        } else {
-            None
+    // lock in the waiter with the id, any forged, stale or already
+    // consumed id would just get the `Miss`
+    fn from_id(space: Space, id: u64) -> Result<Arc<Inner<T>>, Miss>
+    where
+        T: Send + 'static,
+    {
+        registry::take::<Inner<T>>(space, id)
+    }
+
+    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> Result<T, WaitError> {
+        let ret = self.inner.waiter.wait_rsp(timeout);
+        ret.map_err(|e| self.with_key(e))
+    }
+
+    /// wait for the rsp until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> Result<T, WaitError> {
+        let ret = self.inner.waiter.wait_rsp_until(deadline);
+        ret.map_err(|e| self.with_key(e))
+    }
+
+    fn with_key(&self, e: WaitError) -> WaitError {
+        match self.key() {
+            Some(key) => e.with_key(key),
+            None => e,
         }
     }
 
-    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
-        self.waiter.wait_rsp(timeout)
+    /// wait for the rsp from an async context
+    #[cfg(feature = "async")]
+    pub fn wait_rsp_async<D: Into<Option<Duration>>>(&self, timeout: D) -> WaitRsp<'_, T> {
+        WaitRsp::new(&self.inner.waiter, self.key(), timeout.into())
+    }
+
+    // the text form of the outstanding id used as the error key
+    fn key(&self) -> Option<String> {
+        let id = match self.inner.key.load(Ordering::Acquire) {
+            0 | ARMING => return None,
+            id => id,
+        };
+        match self.inner.space() {
+            Space::Wide => Some(ID::<T>::from(id).to_string()),
+            Space::Compact => ID32::<T>::try_from(id as u32).ok().map(|id| id.to_string()),
+        }
     }
 
     /// set rsp for the waiter with id
-    /// the `id` must be come from `get_id()`
-    pub fn set_rsp(id: ID, rsp: T) {
-        if let Some(waiter) = Self::from_id(&id) {
-            // clear the id so that we can get the id again
-            waiter.key.store(0, Ordering::Release);
-            // wake up the blocker
-            waiter.waiter.set_rsp(rsp);
+    /// a forged, stale or already consumed `id` is passed to the dead letter
+    /// handler and the rsp is given back with the reason
+    pub fn set_rsp(id: ID<T>, rsp: T) -> Result<(), RspError<T>>
+    where
+        T: Send + 'static,
+    {
+        Self::deliver(Space::Wide, id.0, rsp)
+    }
+
+    /// set rsp for the waiter with the compact id, see `set_rsp`
+    pub fn set_rsp32(id: ID32<T>, rsp: T) -> Result<(), RspError<T>>
+    where
+        T: Send + 'static,
+    {
+        Self::deliver(Space::Compact, u64::from(id.0.get()), rsp)
+    }
+
+    fn deliver(space: Space, id: u64, rsp: T) -> Result<(), RspError<T>>
+    where
+        T: Send + 'static,
+    {
+        match Self::from_id(space, id) {
+            Ok(inner) => {
+                inner.deliver(rsp);
+                Ok(())
+            }
+            Err(miss) => Err(RspError::dead_letter(id, rsp, miss)),
         }
     }
+
+    /// cancel the outstanding id, a later rsp of it is given back with
+    /// `DeadLetterReason::Canceled`, and a new id could be taken, e.g. to
+    /// retry after the wait timed out
+    ///
+    /// the current wait would return `WaitError::Canceled`
+    pub fn cancel(&self) {
+        // disarm the id so that a later rsp is given back, if a racing
+        // `set_rsp` already took the id, it clears the id after the delivery
+        let id = self.inner.key.load(Ordering::Acquire);
+        if id != 0 && registry::remove(self.inner.space(), id, Release::Canceled) {
+            self.inner.key.store(0, Ordering::Release);
+        }
+        self.inner.waiter.cancel_wait();
+    }
+
+    /// install the handler for the rsps that can't be delivered to any
+    /// `TokenWaiter<T>`, it gets the id, the rsp and the reason
+    ///
+    /// it replaces the previous handler of the same `T`
+    pub fn set_dead_letter<F>(handler: F)
+    where
+        T: Send + 'static,
+        F: Fn(u64, &T, DeadLetterReason) + Send + Sync + 'static,
+    {
+        dead_letter::set_token_handler::<T>(Arc::new(handler))
+    }
+
+    /// the dead letter counters of all the `TokenWaiter<T>`
+    pub fn dead_letter_stats() -> DeadLetterStats
+    where
+        T: Send + 'static,
+    {
+        dead_letter::token_stats::<T>()
+    }
+}
+
+impl<T> AsWaiter<T> for TokenWaiter<T> {
+    fn waiter(&self) -> &Waiter<T> {
+        &self.inner.waiter
+    }
+
+    fn key(&self) -> Option<String> {
+        TokenWaiter::key(self)
+    }
+
+    fn cancel(&self) {
+        TokenWaiter::cancel(self)
+    }
 }
 
 impl<T> fmt::Debug for TokenWaiter<T> {
@@ -115,20 +347,16 @@ impl<T> Default for TokenWaiter<T> {
     }
 }
 
-// this is not necessary, we safely drop a non triggered token waiter
-// impl<T> Drop for TokenWaiter<T> {
-//     fn drop(&mut self) {
-//         // wait for the key locked and clear it
-//         let mut key = self.key.load(Ordering::Relaxed) & !1;
-//         while let Err(v) =
-//             self.key
-//                 .compare_exchange_weak(key, 0, Ordering::AcqRel, Ordering::Relaxed)
-//         {
-//             key = v;
-//             std::hint::spin_loop()
-//         }
-//     }
-// }
+impl<T> Drop for TokenWaiter<T> {
+    fn drop(&mut self) {
+        // disarm the outstanding id, a racing `set_rsp` either takes the
+        // registry slot first or observes the waiter as gone
+        let id = self.inner.key.load(Ordering::Acquire);
+        if id != 0 {
+            registry::remove(self.inner.space(), id, Release::Dropped);
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -140,7 +368,7 @@ mod tests {
         let waiter = TokenWaiter::<usize>::new();
         assert!(waiter.id().is_ok());
         // the previous id should be consumed
-        assert!(waiter.id().is_err());
+        assert_eq!(waiter.id().unwrap_err(), Error::InUse);
     }
 
     #[test]
@@ -177,12 +405,266 @@ mod tests {
             });
             // this will block until the rsp was set
             let ret = waiter.wait_rsp(Duration::from_millis(100));
-            h.join().unwrap();
+            h.join().unwrap().ok();
             ret
         })
         .join()
         .unwrap();
 
-        assert_eq!(result.is_err(), true);
+        assert!(result.is_err());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn token_waiter_async() {
+        use futures_executor::block_on;
+        let waiter = TokenWaiter::<usize>::new();
+        let id = waiter.id().unwrap();
+        go!(move || {
+            may::coroutine::sleep(Duration::from_millis(10));
+            TokenWaiter::set_rsp(id, 42usize)
+        });
+        assert_eq!(block_on(waiter.wait_rsp_async(None)).unwrap(), 42);
+
+        let _id = waiter.id().unwrap();
+        let ret = block_on(waiter.wait_rsp_async(Duration::from_millis(10)));
+        assert!(ret.unwrap_err().is_timeout());
+    }
+
+    #[test]
+    fn token_waiter_bad_id() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id: u64 = waiter.id().unwrap().into();
+        // forged ids are just ignored
+        for bad in [0, 1, u64::MAX, id ^ 1, id.wrapping_add(1 << 32)] {
+            assert!(TokenWaiter::set_rsp(ID::from(bad), 0usize).is_err());
+        }
+        // an id of another type is ignored too
+        let err = TokenWaiter::<String>::set_rsp(ID::from(id), String::new()).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::NeverRegistered);
+        assert!(waiter.wait_rsp(Duration::from_millis(10)).is_err());
+
+        TokenWaiter::set_rsp(ID::from(id), 42usize).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+        // duplicated or stale id is rejected
+        let err = TokenWaiter::set_rsp(ID::from(id), 43usize).unwrap_err();
+        assert!(err.is_consumed());
+        assert_eq!(err.into_rsp(), 43);
+        let new_id: u64 = waiter.id().unwrap().into();
+        assert_ne!(id, new_id);
+        let err = TokenWaiter::set_rsp(ID::from(id), 44usize).unwrap_err();
+        assert!(err.is_consumed());
+        assert!(waiter.wait_rsp(Duration::from_millis(10)).is_err());
+
+        // the canceled id is stale
+        waiter.cancel();
+        let err = TokenWaiter::set_rsp(ID::from(new_id), 45usize).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::Canceled);
+    }
+
+    #[test]
+    fn token_waiter_retry() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id = waiter.id().unwrap();
+        let err = waiter.wait_rsp(Duration::from_millis(10)).unwrap_err();
+        // the key is the text form of the id
+        assert_eq!(err.key(), Some(id.to_string().as_str()));
+        assert_eq!(waiter.id().unwrap_err(), Error::InUse);
+
+        // retry with a new id, the rsp of the first try is given back
+        waiter.cancel();
+        let retry = waiter.id().unwrap();
+        let err = TokenWaiter::set_rsp(id, 1usize).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::Canceled);
+        TokenWaiter::set_rsp(retry, 2usize).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 2);
+    }
+
+    #[test]
+    fn token_waiter_cancel_race() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id: u64 = waiter.id().unwrap().into();
+        // a racing `set_rsp` takes the id right before the cancel
+        let inner = TokenWaiter::<usize>::from_id(Space::Wide, id).unwrap();
+        waiter.cancel();
+        // the id can't be armed again until the rsp is delivered
+        assert_eq!(waiter.id().unwrap_err(), Error::InUse);
+        inner.deliver(1);
+
+        // the late rsp is not seen by the next round
+        let id = waiter.id().unwrap();
+        assert!(waiter.wait_rsp(Duration::from_millis(10)).is_err());
+        TokenWaiter::set_rsp(id, 2usize).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 2);
+    }
+
+    #[test]
+    fn token_waiter_arm_race() {
+        let waiter = TokenWaiter::<usize>::new();
+        // only one of the racing callers arms the waiter
+        let ids: Vec<_> = std::thread::scope(|s| {
+            let ids: Vec<_> = (0..8).map(|_| s.spawn(|| waiter.id())).collect();
+            ids.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(ids.iter().filter(|id| id.is_ok()).count(), 1);
+        assert!(ids
+            .iter()
+            .flatten()
+            .all(|id| waiter.key() == Some(id.to_string())));
+        let id = ids.into_iter().find_map(Result::ok).unwrap();
+        TokenWaiter::set_rsp(id, 1usize).unwrap();
+        assert_eq!(waiter.wait_rsp(None), Ok(1));
+    }
+
+    #[test]
+    fn token_waiter_drop() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id = waiter.id().unwrap();
+        drop(waiter);
+        // the waiter is gone, the rsp is given back
+        let err = TokenWaiter::set_rsp(id, 42usize).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::TimedOut);
+        assert_eq!(err.into_rsp(), 42);
+    }
+
+    #[test]
+    fn token_waiter_id32() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id: u32 = waiter.id32().unwrap().into();
+        assert_eq!(waiter.id32().unwrap_err(), Error::InUse);
+        let err = waiter.wait_rsp(Duration::ZERO).unwrap_err();
+        let text = ID32::<usize>::try_from(id).unwrap().to_string();
+        assert_eq!(err.key(), Some(text.as_str()));
+        // the compact id round trips through the 32 bit field
+        let id = ID32::try_from(id).unwrap();
+        TokenWaiter::set_rsp32(id, 42usize).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+
+        // compact and wide ids don't mix
+        let id: u32 = waiter.id32().unwrap().into();
+        let err = TokenWaiter::set_rsp(ID::from(u64::from(id)), 0usize).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::NeverRegistered);
+        waiter.cancel();
+        let err = TokenWaiter::set_rsp32(ID32::try_from(id).unwrap(), 1usize).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::Canceled);
+
+        assert_eq!(ID32::<usize>::try_from(0).unwrap_err(), Error::Invalid);
+    }
+
+    #[test]
+    fn token_waiter_move() {
+        let waiter = TokenWaiter::<usize>::new();
+        let id = waiter.id().unwrap();
+        // move the waiter around after the id is taken
+        let waiter = Box::new(waiter);
+        let mut waiters = vec![*waiter];
+        waiters.push(TokenWaiter::new());
+        let waiter = waiters.swap_remove(0);
+        TokenWaiter::set_rsp(id, 42usize).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 42);
+    }
+
+    #[test]
+    fn token_waiter_drop_race() {
+        use std::sync::Barrier;
+        use std::thread;
+        // a stress loop of the race, the deterministic interleavings are
+        // covered by `token_waiter_drop_interleave`
+        for _ in 0..1000 {
+            let waiter = TokenWaiter::<Box<usize>>::new();
+            let id = waiter.id().unwrap();
+            let barrier = Arc::new(Barrier::new(2));
+            let b = barrier.clone();
+            let h = thread::spawn(move || {
+                b.wait();
+                TokenWaiter::set_rsp(id, Box::new(42))
+            });
+            barrier.wait();
+            drop(waiter);
+            // either delivered before the drop or the waiter is observed gone
+            if let Err(e) = h.join().unwrap() {
+                assert_eq!(e.reason(), DeadLetterReason::TimedOut);
+                assert_eq!(*e.into_rsp(), 42);
+            }
+        }
+    }
+
+    #[test]
+    fn token_waiter_drop_interleave() {
+        // `set_rsp` takes the id, then the waiter is dropped before delivery
+        let waiter = TokenWaiter::<Box<usize>>::new();
+        let id: u64 = waiter.id().unwrap().into();
+        let inner = TokenWaiter::<Box<usize>>::from_id(Space::Wide, id).unwrap();
+        drop(waiter);
+        // the shared state outlives the waiter, the rsp is dropped with it
+        assert_eq!(Arc::strong_count(&inner), 1);
+        inner.deliver(Box::new(42));
+        drop(inner);
+
+        // the waiter is dropped, then `set_rsp` finds the id disarmed
+        let waiter = TokenWaiter::<Box<usize>>::new();
+        let id = waiter.id().unwrap();
+        drop(waiter);
+        let err = TokenWaiter::set_rsp(id, Box::new(42)).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::TimedOut);
+    }
+
+    #[test]
+    fn token_waiter_timeout_then_drop() {
+        // the waiting coroutine times out and exits with the id outstanding
+        let (id, h) = {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let h = go!(move || {
+                let waiter = TokenWaiter::<Box<usize>>::new();
+                tx.send(waiter.id().unwrap()).unwrap();
+                waiter.wait_rsp(Duration::from_millis(10))
+            });
+            (rx.recv().unwrap(), h)
+        };
+        assert!(h.join().unwrap().unwrap_err().is_timeout());
+        let err = TokenWaiter::set_rsp(id, Box::new(42)).unwrap_err();
+        assert_eq!(err.reason(), DeadLetterReason::TimedOut);
+    }
+
+    #[test]
+    fn token_waiter_dead_letter() {
+        use std::sync::Mutex;
+        // a private rsp type so the other tests don't mess up the counters
+        #[derive(Debug, PartialEq)]
+        struct Rsp(usize);
+        static LETTERS: Mutex<Vec<(usize, DeadLetterReason)>> = Mutex::new(Vec::new());
+        TokenWaiter::<Rsp>::set_dead_letter(|_id, rsp: &Rsp, reason| {
+            LETTERS.lock().unwrap().push((rsp.0, reason));
+        });
+
+        let waiter = TokenWaiter::<Rsp>::new();
+        let id: u64 = waiter.id().unwrap().into();
+        TokenWaiter::set_rsp(id.into(), Rsp(1)).unwrap();
+        TokenWaiter::set_rsp(id.into(), Rsp(2)).ok();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), Rsp(1));
+
+        let id: u64 = waiter.id().unwrap().into();
+        waiter.cancel();
+        TokenWaiter::set_rsp(id.into(), Rsp(3)).ok();
+
+        let id: u64 = waiter.id().unwrap().into();
+        drop(waiter);
+        TokenWaiter::set_rsp(id.into(), Rsp(4)).ok();
+        TokenWaiter::set_rsp(0.into(), Rsp(5)).ok();
+
+        assert_eq!(
+            *LETTERS.lock().unwrap(),
+            [
+                (2, DeadLetterReason::Consumed),
+                (3, DeadLetterReason::Canceled),
+                (4, DeadLetterReason::TimedOut),
+                (5, DeadLetterReason::NeverRegistered),
+            ]
+        );
+        let stats = TokenWaiter::<Rsp>::dead_letter_stats();
+        assert_eq!(stats.consumed, 1);
+        assert_eq!(stats.canceled, 1);
+        assert_eq!(stats.timed_out, 1);
+        assert_eq!(stats.never_registered, 1);
     }
 }
@@ -0,0 +1,55 @@
+use futures_timer::Delay;
+
+use crate::error::WaitError;
+use crate::waiter::Waiter;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Future returned by the `wait_rsp_async` methods
+///
+/// it resolves the same way as the blocking `wait_rsp`
+pub struct WaitRsp<'a, T> {
+    waiter: &'a Waiter<T>,
+    key: Option<String>,
+    start: Instant,
+    delay: Option<Delay>,
+}
+
+impl<'a, T> WaitRsp<'a, T> {
+    pub(crate) fn new(
+        waiter: &'a Waiter<T>,
+        key: Option<String>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        WaitRsp {
+            waiter,
+            key,
+            start: Instant::now(),
+            delay: timeout.map(Delay::new),
+        }
+    }
+}
+
+impl<'a, T> Future for WaitRsp<'a, T> {
+    type Output = Result<T, WaitError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ret = match self.waiter.poll_rsp(cx) {
+            Poll::Ready(ret) => ret,
+            Poll::Pending => match self.delay.as_mut().map(|delay| Pin::new(delay).poll(cx)) {
+                Some(Poll::Ready(_)) => Err(WaitError::Timeout {
+                    key: None,
+                    elapsed: self.start.elapsed(),
+                }),
+                _ => return Poll::Pending,
+            },
+        };
+        Poll::Ready(ret.map_err(|e| match self.key.take() {
+            Some(key) => e.with_key(key),
+            None => e,
+        }))
+    }
+}
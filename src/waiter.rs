@@ -1,58 +1,174 @@
-use may::coroutine;
 use may::sync::{AtomicOption, Blocker};
 
-use std::sync::atomic::Ordering;
-use std::time::Duration;
-use std::{fmt, io};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::error::WaitError;
+use crate::select::Watcher;
+
+// the waiter is still waiting for the rsp
+const WAITING: usize = 0;
+// the waiter is canceled
+const CANCELED: usize = 1;
+// the waiter map is shut down
+const SHUTDOWN: usize = 2;
+// the responder is dropped without sending the rsp
+const DROPPED: usize = 3;
+// the waiter is expired by the map sweeper
+const EXPIRED: usize = 4;
 
 /// Generic Waiter that could wait for a response
-pub(crate) struct Waiter<T> {
-    blocker: Blocker,
+///
+/// it's not exported, only reachable through the sealed `AsWaiter` trait
+pub struct Waiter<T> {
     rsp: AtomicOption<Box<T>>,
+    state: AtomicUsize,
+    // how long the waiter lived when expired, in nanos
+    expired_after: AtomicU64,
+    // the blocker of the context that is waiting, a single waiter or a
+    // group of waiters with `wait_any`
+    watcher: Mutex<Option<Arc<Blocker>>>,
+    #[cfg(feature = "async")]
+    waker: Mutex<Option<Waker>>,
 }
 
 impl<T> Waiter<T> {
     pub fn new() -> Self {
         Waiter {
-            blocker: Blocker::new(false),
             rsp: AtomicOption::none(),
+            state: AtomicUsize::new(WAITING),
+            expired_after: AtomicU64::new(0),
+            watcher: Mutex::new(None),
+            #[cfg(feature = "async")]
+            waker: Mutex::new(None),
         }
     }
 
     pub fn set_rsp(&self, rsp: T) {
         // set the response
-        self.rsp.swap(Box::new(rsp), Ordering::Release);
+        self.put_rsp(rsp);
         // wake up the blocker
-        self.blocker.unpark();
+        self.wake();
+    }
+
+    /// set the response without waking up the blocker, call `wake` after
+    pub fn put_rsp(&self, rsp: T) {
+        self.rsp.store(Box::new(rsp));
+    }
+
+    pub fn wake(&self) {
+        if let Some(watcher) = self.watcher.lock().unwrap().as_ref() {
+            watcher.unpark();
+        }
+        #[cfg(feature = "async")]
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    /// install or remove the blocker that is unparked with this waiter
+    pub fn watch(&self, watcher: Option<Arc<Blocker>>) {
+        *self.watcher.lock().unwrap() = watcher;
     }
 
-    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
-        use may::coroutine::ParkError;
-        use std::io::{Error, ErrorKind};
-        let timeout = timeout.into();
+    /// take the rsp or the error without blocking
+    pub fn try_rsp(&self) -> Option<Result<T, WaitError>> {
+        if let Some(rsp) = self.rsp.take() {
+            return Some(Ok(*rsp));
+        }
+        match self.state.load(Ordering::Acquire) {
+            CANCELED => Some(Err(WaitError::Canceled { key: None })),
+            SHUTDOWN => Some(Err(WaitError::Shutdown { key: None })),
+            DROPPED => Some(Err(WaitError::ResponderDropped { key: None })),
+            EXPIRED => Some(Err(WaitError::Timeout {
+                key: None,
+                elapsed: Duration::from_nanos(self.expired_after.load(Ordering::Relaxed)),
+            })),
+            _ => None,
+        }
+    }
+
+    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> Result<T, WaitError> {
+        let start = Instant::now();
+        let deadline = timeout.into().and_then(|t| start.checked_add(t));
+        self.wait_rsp_deadline(start, deadline)
+    }
+
+    /// wait for the rsp until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> Result<T, WaitError> {
+        self.wait_rsp_deadline(Instant::now(), Some(deadline))
+    }
+
+    // park on the blocker of the current context, so the waiter could be
+    // created in one context and waited in another
+    fn wait_rsp_deadline(&self, start: Instant, deadline: Option<Instant>) -> Result<T, WaitError> {
+        let mut watcher = Watcher::new(std::iter::once(self));
+        watcher.start = start;
         loop {
-            match self.blocker.park(timeout) {
-                Ok(_) => {
-                    if let Some(rsp) = self.rsp.take(Ordering::Acquire) {
-                        return Ok(*rsp);
-                        // None => Err(Error::new(ErrorKind::Other, "unable to get the rsp")),
-                        // false wake up try again
-                        // None => {}
-                    }
-                }
-                Err(ParkError::Timeout) => {
-                    return Err(Error::new(ErrorKind::TimedOut, "wait rsp timeout"))
-                }
-                Err(ParkError::Canceled) => {
-                    coroutine::trigger_cancel_panic();
-                }
+            if let Some(ret) = self.try_rsp() {
+                return ret;
             }
+            // a false wake up should not reset the timeout
+            watcher.park(deadline)?;
         }
     }
 
     pub fn cancel_wait(&self) {
+        self.close(CANCELED)
+    }
+
+    /// return true if the waiter is canceled or shut down
+    pub fn is_canceled(&self) -> bool {
+        matches!(self.state.load(Ordering::Acquire), CANCELED | SHUTDOWN)
+    }
+
+    /// clear the canceled state and drop the rsp left by the last round,
+    /// so that the waiter could be used again
+    pub fn reset(&self) {
+        self.rsp.take();
+        self.state.store(WAITING, Ordering::Release);
+    }
+
+    /// wake up the waiter with a shutdown error
+    pub fn shutdown(&self) {
+        self.close(SHUTDOWN)
+    }
+
+    /// wake up the waiter with a responder dropped error
+    pub fn responder_dropped(&self) {
+        self.close(DROPPED)
+    }
+
+    /// wake up the waiter with a timeout error, `elapsed` is how long it lived
+    pub fn expire(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.expired_after.store(nanos, Ordering::Relaxed);
+        self.close(EXPIRED)
+    }
+
+    fn close(&self, state: usize) {
+        self.state.store(state, Ordering::Release);
         // wake up the blocker without rsp
-        self.blocker.unpark()
+        self.wake()
+    }
+
+    /// poll the rsp from an async context, the waker is registered
+    /// next to the watcher and is triggered by `set_rsp` or `cancel_wait`
+    #[cfg(feature = "async")]
+    pub fn poll_rsp(&self, cx: &mut Context<'_>) -> Poll<Result<T, WaitError>> {
+        if let Some(ret) = self.try_rsp() {
+            return Poll::Ready(ret);
+        }
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        // check again in case the rsp is set before the waker registered
+        match self.try_rsp() {
+            Some(ret) => Poll::Ready(ret),
+            None => Poll::Pending,
+        }
     }
 }
 
@@ -67,3 +183,28 @@ impl<T> Default for Waiter<T> {
         Waiter::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn wait_rsp_false_wake_up() {
+        let waiter = Arc::new(Waiter::<usize>::new());
+        let w = waiter.clone();
+        let h = std::thread::spawn(move || {
+            // keep waking up the waiter without rsp
+            for _ in 0..20 {
+                std::thread::sleep(Duration::from_millis(10));
+                w.wake();
+            }
+        });
+
+        let start = Instant::now();
+        let ret = waiter.wait_rsp_until(start + Duration::from_millis(50));
+        assert!(ret.unwrap_err().is_timeout());
+        assert!(start.elapsed() < Duration::from_millis(150));
+        h.join().unwrap();
+    }
+}
@@ -1,36 +1,289 @@
+use dashmap::mapref::entry::Entry as MapEntry;
 use dashmap::DashMap;
+use may::coroutine::{self, JoinHandle};
+use may::go;
 
+use crate::dead_letter::{DeadLetterReason, DeadLetterStats, DeadLetters};
+use crate::error::WaitError;
+use crate::select::sealed::AsWaiter;
+use crate::token_waiter::Error as IdError;
+#[cfg(feature = "async")]
+use crate::wait_future::WaitRsp;
 use crate::waiter::Waiter;
 
+use std::collections::HashMap;
 use std::fmt::{self, Debug};
 use std::hash::Hash;
-use std::io;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 /// Water guard to wait the response
 #[derive(Debug)]
 pub struct WaiterGuard<'a, K: Hash + Eq + 'a, T: 'a> {
     owner: &'a WaiterMap<K, T>,
     id: K,
+    waiter: Arc<Waiter<T>>,
 }
 
 impl<'a, K: Hash + Eq + Debug, T> WaiterGuard<'a, K, T> {
+    /// the key of the waiter
+    pub fn key(&self) -> &K {
+        &self.id
+    }
+
     /// wait for response
-    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> io::Result<T> {
-        self.owner.wait_rsp(&self.id, timeout.into())
+    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> Result<T, WaitError> {
+        wait_rsp(&self.id, &self.waiter, Deadline::Timeout(timeout.into()))
+    }
+
+    /// wait for response until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> Result<T, WaitError> {
+        wait_rsp(&self.id, &self.waiter, Deadline::Until(deadline))
+    }
+
+    /// wait for response from an async context
+    #[cfg(feature = "async")]
+    pub fn wait_rsp_async<D: Into<Option<Duration>>>(&self, timeout: D) -> WaitRsp<'_, T> {
+        let key = Some(format!("{:?}", self.id));
+        WaitRsp::new(&self.waiter, key, timeout.into())
+    }
+}
+
+impl<'a, K: Hash + Eq + Debug, T> AsWaiter<T> for WaiterGuard<'a, K, T> {
+    fn waiter(&self) -> &Waiter<T> {
+        &self.waiter
+    }
+
+    fn key(&self) -> Option<String> {
+        Some(format!("{:?}", self.id))
+    }
+
+    fn cancel(&self) {
+        self.owner.cancel_waiter(&self.id, &self.waiter);
     }
 }
 
 impl<'a, K: Hash + Eq, T> Drop for WaiterGuard<'a, K, T> {
     fn drop(&mut self) {
         // remove the entry
-        self.owner.del_waiter(&self.id);
+        self.owner.del_waiter(&self.id, &self.waiter);
+    }
+}
+
+/// Water guard that owns a ref of the map, it could be moved freely
+#[derive(Debug)]
+pub struct OwnedWaiterGuard<K: Hash + Eq, T> {
+    owner: Arc<WaiterMap<K, T>>,
+    id: K,
+    waiter: Arc<Waiter<T>>,
+}
+
+impl<K: Hash + Eq + Debug, T> OwnedWaiterGuard<K, T> {
+    /// the key of the waiter
+    pub fn key(&self) -> &K {
+        &self.id
+    }
+
+    /// wait for response
+    pub fn wait_rsp<D: Into<Option<Duration>>>(&self, timeout: D) -> Result<T, WaitError> {
+        wait_rsp(&self.id, &self.waiter, Deadline::Timeout(timeout.into()))
+    }
+
+    /// wait for response until the deadline
+    pub fn wait_rsp_until(&self, deadline: Instant) -> Result<T, WaitError> {
+        wait_rsp(&self.id, &self.waiter, Deadline::Until(deadline))
+    }
+
+    /// wait for response from an async context
+    #[cfg(feature = "async")]
+    pub fn wait_rsp_async<D: Into<Option<Duration>>>(&self, timeout: D) -> WaitRsp<'_, T> {
+        let key = Some(format!("{:?}", self.id));
+        WaitRsp::new(&self.waiter, key, timeout.into())
+    }
+}
+
+impl<K: Hash + Eq + Debug, T> AsWaiter<T> for OwnedWaiterGuard<K, T> {
+    fn waiter(&self) -> &Waiter<T> {
+        &self.waiter
+    }
+
+    fn key(&self) -> Option<String> {
+        Some(format!("{:?}", self.id))
+    }
+
+    fn cancel(&self) {
+        self.owner.cancel_waiter(&self.id, &self.waiter);
+    }
+}
+
+impl<K: Hash + Eq, T> Drop for OwnedWaiterGuard<K, T> {
+    fn drop(&mut self) {
+        // remove the entry
+        self.owner.del_waiter(&self.id, &self.waiter);
+    }
+}
+
+// the guard owns the waiter, so it's never freed while parking on it
+fn wait_rsp<K: Debug, T>(id: &K, waiter: &Waiter<T>, deadline: Deadline) -> Result<T, WaitError> {
+    let ret = match deadline {
+        Deadline::Timeout(timeout) => waiter.wait_rsp(timeout),
+        Deadline::Until(deadline) => waiter.wait_rsp_until(deadline),
+    };
+    ret.map_err(|e| e.with_key(format!("{id:?}")))
+}
+
+enum Deadline {
+    Timeout(Option<Duration>),
+    Until(Instant),
+}
+
+/// what to do when a new waiter uses a key that is already registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// return the key back and leave the existing waiter untouched
+    Reject,
+    /// cancel the existing waiter and register the new one
+    Replace,
+    /// join the existing waiter, every joined waiter gets a clone of the rsp
+    Join,
+}
+
+// all the waiters that registered with the same key
+struct Entry<T> {
+    waiter: Arc<Waiter<T>>,
+    // waiters joined with `DuplicatePolicy::Join`
+    joined: Vec<Arc<Waiter<T>>>,
+    clone_rsp: Option<fn(&T) -> T>,
+    // the register time and the time to live
+    ttl: Option<(Instant, Duration)>,
+    // the rsp is already set
+    answered: AtomicBool,
+}
+
+impl<T> Entry<T> {
+    fn new(waiter: Arc<Waiter<T>>, ttl: Option<Duration>) -> Self {
+        Entry {
+            waiter,
+            joined: Vec::new(),
+            clone_rsp: None,
+            ttl: ttl.map(|ttl| (Instant::now(), ttl)),
+            answered: AtomicBool::new(false),
+        }
+    }
+
+    // why the entry is removed, a late rsp is a dead letter of the reason
+    fn retired_reason(&self) -> DeadLetterReason {
+        if self.answered.load(Ordering::Relaxed) {
+            DeadLetterReason::Consumed
+        } else if self.waiter.is_canceled() {
+            DeadLetterReason::Canceled
+        } else {
+            DeadLetterReason::TimedOut
+        }
+    }
+
+    // an answered entry is never expired, it's left to its guard
+    fn ttl_reached(&self, now: Instant) -> bool {
+        if self.answered.load(Ordering::Relaxed) {
+            return false;
+        }
+        match self.ttl {
+            Some((start, ttl)) => now.saturating_duration_since(start) >= ttl,
+            None => false,
+        }
+    }
+
+    // wake up the waiters with a timeout error
+    fn expire(&self, now: Instant) {
+        let elapsed = self.ttl.map_or(Duration::ZERO, |(start, _)| {
+            now.saturating_duration_since(start)
+        });
+        self.waiters().for_each(|w| w.expire(elapsed));
+    }
+
+    fn waiters(&self) -> impl Iterator<Item = &Arc<Waiter<T>>> {
+        std::iter::once(&self.waiter).chain(self.joined.iter())
+    }
+
+    fn set_rsp(&self, rsp: T) {
+        self.answered.store(true, Ordering::Relaxed);
+        if let Some(clone_rsp) = self.clone_rsp {
+            self.joined.iter().for_each(|w| w.set_rsp(clone_rsp(&rsp)));
+        }
+        self.waiter.set_rsp(rsp);
+    }
+}
+
+// rsps that arrived before their waiters were registered
+struct EarlyRsps<K, T> {
+    rsps: Mutex<HashMap<K, (T, Instant)>>,
+    capacity: usize,
+    ttl: Duration,
+    clone_key: fn(&K) -> K,
+}
+
+impl<K: Hash + Eq, T> EarlyRsps<K, T> {
+    fn rsps(&self) -> MutexGuard<'_, HashMap<K, (T, Instant)>> {
+        self.rsps.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // park the rsp, give it back if the area is full or the key is parked,
+    // the rsps purged for the room are put in `expired`
+    fn park(&self, id: &K, rsp: T, expired: &mut Vec<(K, T)>) -> Result<(), T> {
+        let mut rsps = self.rsps();
+        if rsps.len() >= self.capacity {
+            expired.extend(self.drain_expired(&mut rsps));
+        }
+        if rsps.len() >= self.capacity || rsps.contains_key(id) {
+            return Err(rsp);
+        }
+        rsps.insert((self.clone_key)(id), (rsp, Instant::now()));
+        Ok(())
+    }
+
+    // take the parked rsp, an expired one is put in `expired`
+    fn take(&self, id: &K, expired: &mut Vec<(K, T)>) -> Option<T> {
+        let (id, (rsp, at)) = self.rsps().remove_entry(id)?;
+        if at.elapsed() < self.ttl {
+            return Some(rsp);
+        }
+        expired.push((id, rsp));
+        None
+    }
+
+    fn purge(&self) -> Vec<(K, T)> {
+        self.drain_expired(&mut self.rsps())
+    }
+
+    fn drain_expired(&self, rsps: &mut HashMap<K, (T, Instant)>) -> Vec<(K, T)> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (id, (rsp, at)) in std::mem::take(rsps) {
+            match now.saturating_duration_since(at) < self.ttl {
+                true => drop(rsps.insert(id, (rsp, at))),
+                false => expired.push((id, rsp)),
+            }
+        }
+        expired
     }
 }
 
 /// Waiter map that could be used to wait response for given keys
 pub struct WaiterMap<K, T> {
-    map: DashMap<K, Waiter<T>>,
+    map: DashMap<K, Entry<T>>,
+    closed: AtomicBool,
+    // the next key for `new_waiter_auto`
+    next_id: AtomicU64,
+    id_stride: u64,
+    // the ttl of the waiters that are not given one
+    default_ttl: Option<Duration>,
+    // number of the entries expired by the ttl
+    expired: AtomicU64,
+    // buffer for the rsps that race ahead of their waiters
+    early: Option<EarlyRsps<K, T>>,
+    // handler for the rsps that no waiter would get
+    dead_letters: Option<DeadLetters<K, T>>,
 }
 
 impl<K: Hash + Eq, T> Debug for WaiterMap<K, T> {
@@ -49,59 +302,452 @@ impl<K: Hash + Eq, T> WaiterMap<K, T> {
     pub fn new() -> Self {
         WaiterMap {
             map: DashMap::new(),
+            closed: AtomicBool::new(false),
+            next_id: AtomicU64::new(0),
+            id_stride: 1,
+            default_ttl: None,
+            expired: AtomicU64::new(0),
+            early: None,
+            dead_letters: None,
         }
     }
 
+    /// create a map that buffers the rsps which arrive before their waiters,
+    /// see `set_early_rsp`
+    pub fn with_early_rsp(capacity: usize, ttl: Duration) -> Self
+    where
+        K: Clone,
+    {
+        let mut map = WaiterMap::new();
+        map.set_early_rsp(capacity, ttl);
+        map
+    }
+
+    /// buffer the rsps which arrive before their waiters
+    ///
+    /// at most `capacity` such rsps are kept, each for `ttl`, a later waiter
+    /// of the key gets the buffered rsp right away, when the buffer is full
+    /// `set_rsp` gives the rsp back as usual, and the expired rsps are passed
+    /// to the dead letter handler
+    pub fn set_early_rsp(&mut self, capacity: usize, ttl: Duration)
+    where
+        K: Clone,
+    {
+        self.early = Some(EarlyRsps {
+            rsps: Mutex::new(HashMap::new()),
+            capacity,
+            ttl,
+            clone_key: K::clone,
+        });
+    }
+
+    /// set the ttl of all the waiters that are not created by
+    /// `try_new_waiter_with_ttl`, e.g. the owned and the auto key ones
+    ///
+    /// the expired waiters are removed by `sweep`
+    pub fn set_default_ttl(&mut self, ttl: Option<Duration>) {
+        self.default_ttl = ttl;
+    }
+
+    /// install the handler for the rsps that find no waiter, it gets the
+    /// key, the rsp and the reason, `set_rsp` still gives the rsp back
+    ///
+    /// the reasons of the last `history` removed keys are remembered to
+    /// tell a late rsp from a bogus one
+    pub fn set_dead_letter<F>(&mut self, history: usize, handler: F)
+    where
+        K: Clone,
+        F: Fn(&K, &T, DeadLetterReason) + Send + Sync + 'static,
+    {
+        self.dead_letters = Some(DeadLetters::new(history, Box::new(handler)));
+    }
+
+    /// the dead letter counters, all zero if no handler is installed
+    pub fn dead_letter_stats(&self) -> DeadLetterStats {
+        self.dead_letters
+            .as_ref()
+            .map_or_else(DeadLetterStats::default, |d| d.stats())
+    }
+
     /// return a waiter on the stack!
-    pub fn new_waiter(&self, id: K) -> WaiterGuard<K, T>
+    ///
+    /// panics if the key is already in use, the existing waiter is untouched
+    pub fn new_waiter(&self, id: K) -> WaiterGuard<'_, K, T>
     where
         K: Clone,
     {
-        // if we add a same key, the old waiter would be lost!
-        match self.map.insert(id.clone(), Waiter::new()) {
-            Some(_w) => panic!("waiter id already in use!"),
-            None => WaiterGuard { owner: self, id },
+        match self.try_new_waiter(id) {
+            Ok(waiter) => waiter,
+            Err(_) => panic!("waiter id already in use!"),
         }
     }
 
-    fn del_waiter(&self, id: &K) -> Option<Waiter<T>> {
-        self.map.remove(id).map(|v| v.1)
+    /// return a waiter, or give back the key if it's already in use
+    pub fn try_new_waiter(&self, id: K) -> Result<WaiterGuard<'_, K, T>, K>
+    where
+        K: Clone,
+    {
+        match self.add_waiter(&id, DuplicatePolicy::Reject, None, None, true) {
+            Some(waiter) => Ok(WaiterGuard {
+                owner: self,
+                id,
+                waiter,
+            }),
+            None => Err(id),
+        }
     }
 
-    fn wait_rsp(&self, id: &K, timeout: Option<Duration>) -> io::Result<T>
+    // register all the keys or none of them, give back the first key that is
+    // already in use, the keys registered before it are removed on drop
+    //
+    // the buffered early rsps are taken only after all the keys are in, so a
+    // rollback never loses them
+    pub(crate) fn try_new_waiters<I: IntoIterator<Item = K>>(
+        &self,
+        keys: I,
+    ) -> Result<Vec<WaiterGuard<'_, K, T>>, K>
     where
-        K: Debug,
+        K: Clone,
     {
-        fn extend_lifetime<'a, T>(r: &T) -> &'a T {
-            unsafe { ::std::mem::transmute(r) }
+        let guards = keys
+            .into_iter()
+            .map(
+                |id| match self.add_waiter(&id, DuplicatePolicy::Reject, None, None, false) {
+                    Some(waiter) => Ok(WaiterGuard {
+                        owner: self,
+                        id,
+                        waiter,
+                    }),
+                    None => Err(id),
+                },
+            )
+            .collect::<Result<Vec<_>, K>>()?;
+        if self.early.is_some() {
+            let mut expired = Vec::new();
+            for guard in &guards {
+                // the entry may be gone or replaced meanwhile
+                match self.map.get(&guard.id) {
+                    Some(entry) if Arc::ptr_eq(&entry.waiter, &guard.waiter) => {
+                        self.answer_early(&guard.id, &entry, &mut expired)
+                    }
+                    _ => {}
+                }
+            }
+            self.dead_letter_all(expired);
         }
+        Ok(guards)
+    }
 
-        let waiter = match self.map.get(id) {
-            // extends the lifetime of the waiter ref
-            Some(v) => extend_lifetime(&*v),
-            None => unreachable!("can't find id in waiter map!"),
-        };
+    /// return a waiter, a duplicated key is handled by the `policy`
+    ///
+    /// only `DuplicatePolicy::Reject` would give back the key
+    pub fn new_waiter_with(
+        &self,
+        id: K,
+        policy: DuplicatePolicy,
+    ) -> Result<WaiterGuard<'_, K, T>, K>
+    where
+        K: Clone,
+        T: Clone,
+    {
+        match self.add_waiter(&id, policy, Some(T::clone), None, true) {
+            Some(waiter) => Ok(WaiterGuard {
+                owner: self,
+                id,
+                waiter,
+            }),
+            None => Err(id),
+        }
+    }
+
+    /// return a waiter that owns a ref of the map
+    ///
+    /// panics if the key is already in use, the existing waiter is untouched
+    pub fn new_owned_waiter(self: &Arc<Self>, id: K) -> OwnedWaiterGuard<K, T>
+    where
+        K: Clone,
+    {
+        match self.try_new_owned_waiter(id) {
+            Ok(waiter) => waiter,
+            Err(_) => panic!("waiter id already in use!"),
+        }
+    }
+
+    /// return a waiter that owns a ref of the map, or give back the key if
+    /// it's already in use
+    pub fn try_new_owned_waiter(self: &Arc<Self>, id: K) -> Result<OwnedWaiterGuard<K, T>, K>
+    where
+        K: Clone,
+    {
+        match self.add_waiter(&id, DuplicatePolicy::Reject, None, None, true) {
+            Some(waiter) => Ok(OwnedWaiterGuard {
+                owner: self.clone(),
+                id,
+                waiter,
+            }),
+            None => Err(id),
+        }
+    }
+
+    /// return a waiter that expires after `ttl`, or give back the key if
+    /// it's already in use
+    ///
+    /// the expired waiter is removed by `sweep` and its wait would return
+    /// `WaitError::Timeout`
+    pub fn try_new_waiter_with_ttl(&self, id: K, ttl: Duration) -> Result<WaiterGuard<'_, K, T>, K>
+    where
+        K: Clone,
+    {
+        match self.add_waiter(&id, DuplicatePolicy::Reject, None, Some(ttl), true) {
+            Some(waiter) => Ok(WaiterGuard {
+                owner: self,
+                id,
+                waiter,
+            }),
+            None => Err(id),
+        }
+    }
+
+    fn add_waiter(
+        &self,
+        id: &K,
+        policy: DuplicatePolicy,
+        clone_rsp: Option<fn(&T) -> T>,
+        ttl: Option<Duration>,
+        take_early: bool,
+    ) -> Option<Arc<Waiter<T>>>
+    where
+        K: Clone,
+    {
+        let waiter = Arc::new(Waiter::new());
+        let ttl = ttl.or(self.default_ttl);
+        let mut expired = Vec::new();
+        match self.map.entry(id.clone()) {
+            MapEntry::Vacant(entry) => {
+                let entry = entry.insert(Entry::new(waiter.clone(), ttl));
+                if take_early {
+                    self.answer_early(id, &entry, &mut expired);
+                }
+                self.revive(id);
+            }
+            MapEntry::Occupied(mut entry) => match policy {
+                DuplicatePolicy::Reject => return None,
+                DuplicatePolicy::Replace => {
+                    let old = entry.insert(Entry::new(waiter.clone(), ttl));
+                    old.waiters().for_each(|w| w.cancel_wait());
+                    self.revive(id);
+                }
+                DuplicatePolicy::Join => {
+                    let entry = entry.get_mut();
+                    entry.joined.push(waiter.clone());
+                    entry.clone_rsp = clone_rsp;
+                }
+            },
+        }
+        self.dead_letter_all(expired);
+
+        // the map is shut down, the wait would fail right away
+        if self.closed.load(Ordering::SeqCst) {
+            waiter.shutdown();
+        }
+        Some(waiter)
+    }
+
+    // the rsp is already there, the entry is answered right away, unless it
+    // got another rsp meanwhile
+    fn answer_early(&self, id: &K, entry: &Entry<T>, expired: &mut Vec<(K, T)>)
+    where
+        K: Clone,
+    {
+        let rsp = self.early.as_ref().and_then(|e| e.take(id, expired));
+        match rsp {
+            Some(rsp) if entry.answered.load(Ordering::Relaxed) => expired.push((id.clone(), rsp)),
+            Some(rsp) => entry.set_rsp(rsp),
+            None => {}
+        }
+    }
+
+    // the key is registered again, forget why it was retired
+    fn revive(&self, id: &K) {
+        if let Some(dead_letters) = &self.dead_letters {
+            dead_letters.revive(id);
+        }
+    }
 
-        waiter.wait_rsp(timeout)
+    // cancel the waiter and unregister it, a late rsp is a dead letter
+    fn cancel_waiter(&self, id: &K, waiter: &Arc<Waiter<T>>) {
+        waiter.cancel_wait();
+        self.del_waiter(id, waiter);
+    }
+
+    fn del_waiter(&self, id: &K, waiter: &Arc<Waiter<T>>) {
+        // the entry is removed only when its last waiter leaves, and it may
+        // already be replaced by another waiter
+        let removed = self.map.remove_if_mut(id, |_, e| {
+            if !Arc::ptr_eq(&e.waiter, waiter) {
+                e.joined.retain(|w| !Arc::ptr_eq(w, waiter));
+                return false;
+            }
+            match e.joined.pop() {
+                // promote a joined waiter, it would get the rsp instead
+                Some(joined) => {
+                    e.waiter = joined;
+                    false
+                }
+                None => true,
+            }
+        });
+        if let (Some((id, entry)), Some(dead_letters)) = (removed, &self.dead_letters) {
+            dead_letters.retire(&id, entry.retired_reason());
+        }
     }
 
     /// set rsp for the corresponding waiter
+    ///
+    /// if the map is created by `with_early_rsp` an unmatched rsp is buffered
+    /// for the coming waiter
     pub fn set_rsp(&self, id: &K, rsp: T) -> Result<(), T>
     where
         K: Debug,
     {
-        match self.map.get(id) {
-            Some(waiter) => {
-                waiter.set_rsp(rsp);
-                Ok(())
-            }
+        if let Some(entry) = self.map.get(id) {
+            entry.set_rsp(rsp);
+            return Ok(());
+        }
+        let mut expired = Vec::new();
+        let ret = match &self.early {
+            // hold the key so that a new waiter can't slip in before parking
+            Some(early) => match self.map.entry((early.clone_key)(id)) {
+                MapEntry::Occupied(entry) => {
+                    entry.get().set_rsp(rsp);
+                    Ok(())
+                }
+                // give it back if the buffer is full
+                MapEntry::Vacant(_entry) => early.park(id, rsp, &mut expired),
+            },
             None => Err(rsp),
+        };
+        // call the handler without the map locked
+        self.dead_letter_all(expired);
+        if let (Err(rsp), Some(dead_letters)) = (&ret, &self.dead_letters) {
+            dead_letters.handle(id, rsp);
+        }
+        ret
+    }
+
+    // pass the expired early rsps to the dead letter handler
+    fn dead_letter_all(&self, rsps: Vec<(K, T)>) {
+        if let Some(dead_letters) = &self.dead_letters {
+            rsps.iter()
+                .for_each(|(id, rsp)| dead_letters.handle(id, rsp));
         }
     }
 
-    /// cancel all the waiting waiter, all wait would return NotFound error
-    pub fn cancel_all(&mut self) {
-        self.map.iter().for_each(|waiter| waiter.cancel_wait());
+    /// cancel all the waiting waiter, all wait would return `WaitError::Canceled`
+    pub fn cancel_all(&self) {
+        self.map
+            .iter()
+            .for_each(|entry| entry.waiters().for_each(|w| w.cancel_wait()));
+    }
+
+    /// remove the expired entries, their waits would return
+    /// `WaitError::Timeout`, return the number of expired entries
+    ///
+    /// the entries that already got the rsp are not expired
+    pub fn sweep(&self) -> usize {
+        if let Some(early) = &self.early {
+            self.dead_letter_all(early.purge());
+        }
+        let now = Instant::now();
+        let mut expired = 0;
+        self.map.retain(|id, entry| match entry.ttl_reached(now) {
+            true => {
+                if let Some(dead_letters) = &self.dead_letters {
+                    dead_letters.retire(id, DeadLetterReason::TimedOut);
+                }
+                // count it before waking up the waiters
+                self.expired.fetch_add(1, Ordering::Relaxed);
+                entry.expire(now);
+                expired += 1;
+                false
+            }
+            false => true,
+        });
+        expired
+    }
+
+    /// total number of the entries expired by `sweep`
+    pub fn expired_count(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// spawn a coroutine that calls `sweep` every `interval`
+    ///
+    /// the coroutine exits once the map is dropped
+    pub fn spawn_sweeper(self: &Arc<Self>, interval: Duration) -> JoinHandle<()>
+    where
+        K: Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let map = Arc::downgrade(self);
+        go!(move || loop {
+            coroutine::sleep(interval);
+            match map.upgrade() {
+                Some(map) => map.sweep(),
+                None => break,
+            };
+        })
+    }
+
+    /// shut down the map, all current and future waits would return
+    /// `WaitError::Shutdown`
+    pub fn shutdown(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.map
+            .iter()
+            .for_each(|entry| entry.waiters().for_each(|w| w.shutdown()));
+    }
+}
+
+impl<T> WaiterMap<u64, T> {
+    /// create a map that allocates keys, see `set_id_alloc`
+    pub fn with_id_alloc(start: u64, stride: u64) -> Self {
+        let mut map = WaiterMap::new();
+        map.set_id_alloc(start, stride);
+        map
+    }
+
+    /// allocate the keys from `start` with step `stride`
+    ///
+    /// clients that share one id space could use their own `start` with
+    /// the same `stride`, e.g. the instance index and the instance number
+    pub fn set_id_alloc(&mut self, start: u64, stride: u64) {
+        assert!(stride != 0, "id stride must not be zero");
+        self.next_id = AtomicU64::new(start);
+        self.id_stride = stride;
+    }
+
+    /// return a waiter with an allocated key
+    ///
+    /// the key wraps around to the smallest key of the `start + k * stride`
+    /// sequence, so it never runs into the keys of another `start`, and the
+    /// keys that are still in use are skipped, return `IdError::Exhausted`
+    /// if no key of the sequence is free
+    pub fn new_waiter_auto(&self) -> Result<(u64, WaiterGuard<'_, u64, T>), IdError> {
+        let stride = self.id_stride;
+        let next = |id: u64| Some(id.checked_add(stride).unwrap_or(id % stride));
+        // more keys than in use are tried, unless the sequence is that short
+        for _ in 0..=self.map.len() {
+            // the closure always returns `Some`
+            let id = self
+                .next_id
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, next)
+                .unwrap_or_else(|id| id);
+            if let Ok(waiter) = self.try_new_waiter(id) {
+                return Ok((id, waiter));
+            }
+        }
+        Err(IdError::Exhausted)
     }
 }
 
@@ -129,4 +775,380 @@ mod tests {
         let result = waiter.wait_rsp(None).unwrap();
         assert_eq!(result, 100);
     }
+
+    #[test]
+    fn test_cancel_all() {
+        use std::sync::Arc;
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let req_map_1 = req_map.clone();
+
+        let result = go!(move || {
+            let waiter = req_map.new_waiter(1234);
+            waiter.wait_rsp(Duration::from_secs(10))
+        });
+
+        // wait until the waiter is registered
+        while req_map_1.map.is_empty() {
+            may::coroutine::yield_now();
+        }
+        req_map_1.cancel_all();
+
+        let err = result.join().unwrap().unwrap_err();
+        assert_eq!(
+            err,
+            WaitError::Canceled {
+                key: Some("1234".into())
+            }
+        );
+        assert_eq!(
+            std::io::Error::from(err).kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiter = req_map.new_waiter(1);
+        req_map.shutdown();
+        assert!(matches!(
+            waiter.wait_rsp(None),
+            Err(WaitError::Shutdown { .. })
+        ));
+        // new waiters are shut down right away
+        let waiter = req_map.new_waiter(2);
+        let err = waiter.wait_rsp(None).unwrap_err();
+        assert_eq!(err.key(), Some("2"));
+
+        let waiter = WaiterMap::<usize, usize>::new();
+        let err = waiter.new_waiter(3).wait_rsp(Duration::from_millis(10));
+        assert!(err.unwrap_err().is_timeout());
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let err = waiter.new_waiter(4).wait_rsp_until(deadline).unwrap_err();
+        assert_eq!(err.key(), Some("4"));
+        assert!(err.is_timeout());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_waiter_map_async() {
+        use futures_executor::block_on;
+        use std::sync::Arc;
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let req_map_1 = req_map.clone();
+
+        let waiter = req_map.new_waiter(1234);
+        go!(move || {
+            may::coroutine::sleep(Duration::from_millis(10));
+            req_map_1.set_rsp(&1234, 100).ok();
+        });
+        assert_eq!(block_on(waiter.wait_rsp_async(None)).unwrap(), 100);
+
+        // timeout
+        let ret = block_on(waiter.wait_rsp_async(Duration::from_millis(10)));
+        assert!(ret.unwrap_err().is_timeout());
+
+        // cancel
+        req_map.cancel_all();
+        let ret = block_on(waiter.wait_rsp_async(None));
+        assert_eq!(
+            ret.unwrap_err(),
+            WaitError::Canceled {
+                key: Some("1234".into())
+            }
+        );
+    }
+
+    #[test]
+    fn test_duplicate_key() {
+        let req_map = WaiterMap::<usize, usize>::new();
+        let waiter = req_map.new_waiter(1);
+        // reject won't touch the existing waiter
+        assert_eq!(req_map.try_new_waiter(1).unwrap_err(), 1);
+        req_map.set_rsp(&1, 10).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 10);
+
+        // join would get a clone of the rsp
+        let joined = req_map.new_waiter_with(1, DuplicatePolicy::Join).unwrap();
+        req_map.set_rsp(&1, 20).unwrap();
+        assert_eq!(waiter.wait_rsp(None).unwrap(), 20);
+        assert_eq!(joined.wait_rsp(None).unwrap(), 20);
+        drop(joined);
+
+        // the joined waiter stays registered after the first one left
+        let first = req_map.new_waiter(2);
+        let joined = req_map.new_waiter_with(2, DuplicatePolicy::Join).unwrap();
+        drop(first);
+        req_map.set_rsp(&2, 5).unwrap();
+        assert_eq!(joined.wait_rsp(None).unwrap(), 5);
+        drop(joined);
+        assert!(req_map.set_rsp(&2, 6).is_err());
+
+        // replace would cancel the old one
+        let new = req_map
+            .new_waiter_with(1, DuplicatePolicy::Replace)
+            .unwrap();
+        assert!(matches!(
+            waiter.wait_rsp(None),
+            Err(WaitError::Canceled { .. })
+        ));
+        // drop the old guard won't remove the new entry
+        drop(waiter);
+        req_map.set_rsp(&1, 30).unwrap();
+        assert_eq!(new.wait_rsp(None).unwrap(), 30);
+        drop(new);
+        assert!(req_map.map.is_empty());
+    }
+
+    #[test]
+    fn test_auto_id() {
+        let req_map = WaiterMap::<u64, usize>::with_id_alloc(u64::MAX - 3, 2);
+        let _used = req_map.new_waiter(0);
+        let (id1, _w1) = req_map.new_waiter_auto().unwrap();
+        let (id2, _w2) = req_map.new_waiter_auto().unwrap();
+        // wrap around and skip the key in use
+        let (id3, w3) = req_map.new_waiter_auto().unwrap();
+        assert_eq!([id1, id2, id3], [u64::MAX - 3, u64::MAX - 1, 2]);
+
+        req_map.set_rsp(&id3, 42).unwrap();
+        assert_eq!(w3.wait_rsp(None).unwrap(), 42);
+
+        // a stride that doesn't divide 2^64 still keeps the residue class
+        let req_map = WaiterMap::<u64, usize>::with_id_alloc(u64::MAX - 1, 3);
+        let ids: Vec<_> = (0..3)
+            .map(|_| req_map.new_waiter_auto().unwrap().0)
+            .collect();
+        assert_eq!(ids, [u64::MAX - 1, 2, 5]);
+
+        // the sequence of the max stride is just two keys
+        let req_map = WaiterMap::<u64, usize>::with_id_alloc(0, u64::MAX);
+        let _w1 = req_map.new_waiter_auto().unwrap();
+        let _w2 = req_map.new_waiter_auto().unwrap();
+        let err = req_map.new_waiter_auto().unwrap_err();
+        assert_eq!(err, IdError::Exhausted);
+
+        // the setters could be combined
+        let mut req_map = WaiterMap::<u64, usize>::new();
+        req_map.set_id_alloc(7, 10);
+        req_map.set_early_rsp(1, Duration::from_secs(10));
+        req_map.set_rsp(&7, 70).unwrap();
+        let (id, w) = req_map.new_waiter_auto().unwrap();
+        assert_eq!((id, w.wait_rsp(None)), (7, Ok(70)));
+    }
+
+    #[test]
+    fn test_owned_waiter() {
+        use std::sync::Arc;
+        fn assert_send<T: Send + 'static>(_: &T) {}
+
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let waiter = req_map.new_owned_waiter(1234);
+        assert_send(&waiter);
+        assert!(req_map.try_new_owned_waiter(1234).is_err());
+
+        // move the guard to another coroutine
+        let h = go!(move || waiter.wait_rsp(None));
+        while req_map.set_rsp(&1234, 100).is_err() {
+            may::coroutine::yield_now();
+        }
+        assert_eq!(h.join().unwrap().unwrap(), 100);
+        assert!(req_map.map.is_empty());
+    }
+
+    #[test]
+    fn test_wait_while_map_changes() {
+        use std::sync::Arc;
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let req_map_1 = req_map.clone();
+
+        let h = go!(move || {
+            let waiter = req_map_1.new_waiter(0);
+            waiter.wait_rsp(None)
+        });
+
+        // grow and shrink the map while the waiter is parked
+        let guards: Vec<_> = (1..10000).map(|i| req_map.new_waiter(i)).collect();
+        drop(guards);
+        while req_map.set_rsp(&0, 42).is_err() {
+            may::coroutine::yield_now();
+        }
+        assert_eq!(h.join().unwrap().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_ttl_sweeper() {
+        use std::sync::Arc;
+        let req_map = Arc::new(WaiterMap::<usize, usize>::new());
+        let sweeper = req_map.spawn_sweeper(Duration::from_millis(10));
+
+        let _forever = req_map.new_waiter(0);
+        let waiter = req_map
+            .try_new_waiter_with_ttl(1, Duration::from_millis(20))
+            .unwrap();
+        let err = waiter.wait_rsp(Duration::from_secs(10)).unwrap_err();
+        assert!(err.is_timeout());
+        assert_eq!(err.key(), Some("1"));
+        assert_eq!(req_map.expired_count(), 1);
+        // the expired entry is removed
+        assert!(req_map.set_rsp(&1, 1).is_err());
+        req_map.set_rsp(&0, 0).unwrap();
+
+        // the sweeper exits after the map is dropped
+        drop(_forever);
+        drop(waiter);
+        drop(req_map);
+        sweeper.join().unwrap();
+    }
+
+    #[test]
+    fn test_default_ttl() {
+        use std::sync::Arc;
+        let mut req_map = WaiterMap::<u64, usize>::with_id_alloc(0, 1);
+        req_map.set_default_ttl(Some(Duration::from_millis(10)));
+        let req_map = Arc::new(req_map);
+        let owned = req_map.new_owned_waiter(100);
+        let (id, auto) = req_map.new_waiter_auto().unwrap();
+        let joined = req_map.new_waiter_with(id, DuplicatePolicy::Join).unwrap();
+        // the explicit ttl wins
+        let long = req_map
+            .try_new_waiter_with_ttl(101, Duration::from_secs(10))
+            .unwrap();
+
+        // the answered one is not expired
+        let answered = req_map.new_owned_waiter(102);
+        req_map.set_rsp(&102, 2).unwrap();
+
+        may::coroutine::sleep(Duration::from_millis(20));
+        assert_eq!(req_map.sweep(), 2);
+        assert_eq!(req_map.expired_count(), 2);
+        assert_eq!(answered.wait_rsp(None), Ok(2));
+        assert!(owned.wait_rsp(None).unwrap_err().is_timeout());
+        assert!(auto.wait_rsp(None).unwrap_err().is_timeout());
+        assert!(joined.wait_rsp(None).unwrap_err().is_timeout());
+        req_map.set_rsp(&101, 1).unwrap();
+        assert_eq!(long.wait_rsp(None), Ok(1));
+    }
+
+    #[test]
+    fn test_early_rsp() {
+        let req_map = WaiterMap::<usize, usize>::with_early_rsp(2, Duration::from_millis(50));
+        req_map.set_rsp(&1, 10).unwrap();
+        req_map.set_rsp(&2, 20).unwrap();
+        // the buffer is full
+        assert_eq!(req_map.set_rsp(&3, 30), Err(30));
+
+        let w1 = req_map.new_waiter(1);
+        assert_eq!(w1.wait_rsp(Duration::from_millis(10)), Ok(10));
+        // the key is registered as usual while the guard is alive
+        assert!(req_map.try_new_waiter(1).is_err());
+        req_map.set_rsp(&1, 11).unwrap();
+        assert_eq!(w1.wait_rsp(Duration::from_millis(10)), Ok(11));
+        // the buffered rsp is consumed
+        drop(w1);
+        let w1 = req_map.new_waiter(1);
+        assert!(w1.wait_rsp(Duration::from_millis(10)).is_err());
+
+        // the buffered rsp is expired
+        may::coroutine::sleep(Duration::from_millis(60));
+        let w2 = req_map.new_waiter(2);
+        assert!(w2.wait_rsp(Duration::from_millis(10)).is_err());
+
+        // the expired rsps are purged for the new ones
+        req_map.set_rsp(&3, 30).unwrap();
+        assert_eq!(req_map.new_waiter(3).wait_rsp(None), Ok(30));
+
+        // not buffered by default
+        let req_map = WaiterMap::<usize, usize>::new();
+        assert_eq!(req_map.set_rsp(&1, 10), Err(10));
+
+        // the expired rsps are dead letters
+        let mut req_map = WaiterMap::<usize, usize>::with_early_rsp(2, Duration::from_millis(10));
+        let letters = Arc::new(Mutex::new(Vec::new()));
+        let l = letters.clone();
+        req_map.set_dead_letter(16, move |id, rsp, reason| {
+            l.lock().unwrap().push((*id, *rsp, reason));
+        });
+        req_map.set_rsp(&1, 10).unwrap();
+        req_map.set_rsp(&2, 20).unwrap();
+        may::coroutine::sleep(Duration::from_millis(20));
+        // expired on take, on park for the room and by the sweeper
+        assert!(req_map.new_waiter(1).wait_rsp(Duration::ZERO).is_err());
+        req_map.set_rsp(&3, 30).unwrap();
+        req_map.set_rsp(&4, 40).unwrap();
+        may::coroutine::sleep(Duration::from_millis(20));
+        req_map.sweep();
+        let mut letters = letters.lock().unwrap().clone();
+        letters.sort_by_key(|l| l.0);
+        let reason = DeadLetterReason::NeverRegistered;
+        assert_eq!(
+            letters,
+            [
+                (1, 10, reason),
+                (2, 20, reason),
+                (3, 30, reason),
+                (4, 40, reason)
+            ]
+        );
+        assert_eq!(req_map.dead_letter_stats().never_registered, 4);
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        use std::sync::Mutex;
+        let letters = Arc::new(Mutex::new(Vec::new()));
+        let mut req_map = WaiterMap::<usize, usize>::new();
+        let l = letters.clone();
+        req_map.set_dead_letter(16, move |id, rsp, reason| {
+            l.lock().unwrap().push((*id, *rsp, reason));
+        });
+
+        // never registered
+        assert_eq!(req_map.set_rsp(&1, 10), Err(10));
+        // consumed
+        let w = req_map.new_waiter(2);
+        req_map.set_rsp(&2, 20).unwrap();
+        assert_eq!(w.wait_rsp(None), Ok(20));
+        drop(w);
+        assert_eq!(req_map.set_rsp(&2, 21), Err(21));
+        // timed out
+        let w = req_map.new_waiter(3);
+        assert!(w.wait_rsp(Duration::from_millis(10)).is_err());
+        drop(w);
+        assert_eq!(req_map.set_rsp(&3, 30), Err(30));
+        // canceled
+        let w = req_map.new_waiter(4);
+        req_map.cancel_all();
+        drop(w);
+        assert_eq!(req_map.set_rsp(&4, 40), Err(40));
+        // registered again
+        let w = req_map.new_waiter(3);
+        req_map.set_rsp(&3, 31).unwrap();
+        assert_eq!(w.wait_rsp(None), Ok(31));
+
+        assert_eq!(
+            *letters.lock().unwrap(),
+            [
+                (1, 10, DeadLetterReason::NeverRegistered),
+                (2, 21, DeadLetterReason::Consumed),
+                (3, 30, DeadLetterReason::TimedOut),
+                (4, 40, DeadLetterReason::Canceled),
+            ]
+        );
+        let stats = req_map.dead_letter_stats();
+        assert_eq!(stats.never_registered, 1);
+        assert_eq!(stats.consumed, 1);
+        assert_eq!(stats.timed_out, 1);
+        assert_eq!(stats.canceled, 1);
+
+        // a rejected duplicate doesn't forget the retired reason, e.g. the
+        // key is retired by a racing drop right after it's registered again
+        let _w = req_map.new_waiter(7);
+        let dead_letters = req_map.dead_letters.as_ref().unwrap();
+        dead_letters.retire(&7, DeadLetterReason::TimedOut);
+        assert!(req_map.try_new_waiter(7).is_err());
+        assert!(req_map.new_waiter_with(7, DuplicatePolicy::Reject).is_err());
+        dead_letters.handle(&7, &70);
+        let last = letters.lock().unwrap().last().copied();
+        assert_eq!(last, Some((7, 70, DeadLetterReason::TimedOut)));
+    }
 }